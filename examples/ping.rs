@@ -21,7 +21,6 @@ use libp2p::{
         transport::Boxed,
         upgrade::{SelectUpgrade, Version},
     },
-    dns::DnsConfig,
     identity::Keypair,
     mplex::MplexConfig,
     noise::{self, NoiseConfig, X25519Spec},
@@ -163,7 +162,7 @@ impl libp2p::core::Executor for TokioExecutor {
 
 /// Builds a libp2p transport with the following features:
 /// - TCP connectivity over the Tor network
-/// - DNS name resolution
+/// - DNS name resolution by the proxy
 /// - Authentication via noise
 /// - Multiplexing via yamux or mplex
 fn build_transport(
@@ -173,8 +172,7 @@ fn build_transport(
     let dh_keys = noise::Keypair::<X25519Spec>::new().into_authentic(&id_keys)?;
     let noise = NoiseConfig::xx(dh_keys).into_authenticated();
 
-    let tcp = Socks5TokioTcpConfig::default().nodelay(true).onion_map(map);
-    let transport = DnsConfig::new(tcp)?;
+    let transport = Socks5TokioTcpConfig::default().nodelay(true).onion_map(map);
 
     let transport = transport
         .upgrade(Version::V1)
@@ -0,0 +1,691 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Client for the Tor control port.
+//!
+//! Authenticates with any of the methods Tor offers, runs commands and
+//! receives asynchronous events. The transport uses it to create ephemeral
+//! onion services with `ADD_ONION` when listening on a new onion service
+//! address. Tor removes an ephemeral onion service when the control connection
+//! that created it closes, the listener keeps the connection for its lifetime.
+
+use crate::{Connection, Socks5TokioTcpConfig, Socks5TransportError};
+use data_encoding::{HEXLOWER_PERMISSIVE, HEXUPPER};
+use hmac::{Hmac, Mac, NewMac};
+use libp2p::core::multiaddr::{Multiaddr, Onion3Addr, Protocol};
+use sha2::Sha256;
+use std::{
+    collections::VecDeque,
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// Status code of asynchronous event replies.
+const EVENT_STATUS: u16 = 650;
+/// Length of the authentication cookie Tor writes to its cookie file.
+const COOKIE_LEN: usize = 32;
+/// HMAC key for the hash Tor sends in reply to `AUTHCHALLENGE`.
+const SERVER_HASH_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+/// HMAC key for the hash we authenticate with after `AUTHCHALLENGE`.
+const CLIENT_HASH_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+/// Listen address asking for a new onion service reachable at
+/// `virtual_port`, its onion address is reported as a `NewAddress` event once
+/// Tor created it.
+///
+/// Listening on it requires a control port, see
+/// `Socks5TokioTcpConfig::control_port`.
+pub fn new_onion_service(virtual_port: u16) -> Multiaddr {
+    Protocol::Onion3(Onion3Addr::from(([0; 35], virtual_port))).into()
+}
+
+/// The virtual port if `addr` asks for a new onion service.
+pub(crate) fn new_onion_service_port(addr: &Multiaddr) -> Option<u16> {
+    let mut iter = addr.iter();
+    match (iter.next(), iter.next()) {
+        (Some(Protocol::Onion3(onion)), None) if onion.hash().iter().all(|b| *b == 0) => {
+            Some(onion.port())
+        }
+        _ => None,
+    }
+}
+
+impl Socks5TokioTcpConfig {
+    /// Opens an authenticated connection to the configured control port, the
+    /// authentication method is picked from the ones Tor offers.
+    pub async fn control(&self) -> Result<TorControl, Socks5TransportError> {
+        let addr = self.control_port.as_ref().ok_or_else(|| {
+            let e = io::Error::new(io::ErrorKind::NotFound, "no control port configured");
+            Socks5TransportError::Control(e)
+        })?;
+        let connect = async {
+            let mut control = TorControl::connect(addr).await?;
+            control
+                .authenticate(self.control_password.as_ref().map(|p| p.0.as_str()))
+                .await?;
+            Ok::<_, io::Error>(control)
+        };
+        connect.await.map_err(Socks5TransportError::Control)
+    }
+}
+
+/// Address of the Tor control port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlAddr {
+    /// A socket address, IPv4 or IPv6.
+    Socket(SocketAddr),
+    /// Path of a Unix domain socket e.g., Tor's `ControlPort
+    /// unix:/run/tor/control`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ControlAddr {
+    /// The control port listening on localhost at `port`.
+    pub fn localhost(port: u16) -> Self {
+        ControlAddr::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)))
+    }
+
+    /// Opens a connection to the control port.
+    async fn connect(&self) -> io::Result<Connection> {
+        let conn = match self {
+            ControlAddr::Socket(addr) => Connection::Tcp(TcpStream::connect(*addr).await?),
+            #[cfg(unix)]
+            ControlAddr::Unix(path) => Connection::Unix(UnixStream::connect(path).await?),
+        };
+        Ok(conn)
+    }
+}
+
+impl From<SocketAddr> for ControlAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ControlAddr::Socket(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<PathBuf> for ControlAddr {
+    fn from(path: PathBuf) -> Self {
+        ControlAddr::Unix(path)
+    }
+}
+
+/// The control port password, kept out of `Debug` output.
+#[derive(Clone)]
+pub(crate) struct ControlPassword(pub(crate) String);
+
+impl fmt::Debug for ControlPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ControlPassword")
+            .field(&"<redacted>")
+            .finish()
+    }
+}
+
+/// How to authenticate with the control port.
+#[derive(Clone, PartialEq, Eq)]
+pub enum ControlAuth {
+    /// No authentication, Tor accepts any controller.
+    Null,
+    /// A password matching Tor's `HashedControlPassword`.
+    HashedPassword(String),
+    /// Send the content of the cookie file.
+    Cookie(PathBuf),
+    /// Prove knowledge of the cookie file with an HMAC challenge, without
+    /// sending the cookie.
+    SafeCookie(PathBuf),
+}
+
+impl fmt::Debug for ControlAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlAuth::Null => f.write_str("Null"),
+            ControlAuth::HashedPassword(_) => f
+                .debug_tuple("HashedPassword")
+                .field(&"<redacted>")
+                .finish(),
+            ControlAuth::Cookie(file) => f.debug_tuple("Cookie").field(file).finish(),
+            ControlAuth::SafeCookie(file) => f.debug_tuple("SafeCookie").field(file).finish(),
+        }
+    }
+}
+
+/// Reply to `PROTOCOLINFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    /// The authentication methods Tor accepts e.g., `SAFECOOKIE`.
+    pub auth_methods: Vec<String>,
+    /// The cookie file for the `COOKIE` and `SAFECOOKIE` methods.
+    pub cookie_file: Option<PathBuf>,
+    /// The version of Tor.
+    pub tor_version: Option<String>,
+}
+
+/// A reply, or asynchronous event, from the control port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlReply {
+    /// The status code e.g., 250 for success or 650 for events.
+    pub status: u16,
+    /// The lines of the reply without the status code, a data block is
+    /// appended to its line separated by a newline.
+    pub lines: Vec<String>,
+}
+
+/// A connection to the Tor control port.
+#[derive(Debug)]
+pub struct TorControl {
+    stream: BufReader<Connection>,
+    /// Events received while waiting for the reply to a command.
+    events: VecDeque<ControlReply>,
+    /// Tor answers `PROTOCOLINFO` only once before authentication.
+    protocol_info: Option<ProtocolInfo>,
+}
+
+impl TorControl {
+    /// Connects to the control port at `addr`, the connection must be
+    /// authenticated before any other command than `PROTOCOLINFO`.
+    pub async fn connect(addr: &ControlAddr) -> io::Result<Self> {
+        Ok(Self {
+            stream: BufReader::new(addr.connect().await?),
+            events: VecDeque::new(),
+            protocol_info: None,
+        })
+    }
+
+    /// Sends `command` and returns its reply, fails if the status is not
+    /// success.
+    pub async fn command(&mut self, command: &str) -> io::Result<ControlReply> {
+        let command = format!("{}\r\n", command);
+        self.stream.get_mut().write_all(command.as_bytes()).await?;
+
+        loop {
+            let reply = self.read_reply().await?;
+            if reply.status == EVENT_STATUS {
+                self.events.push_back(reply);
+                continue;
+            }
+            if reply.status / 100 != 2 {
+                let msg = format!(
+                    "control port replied {} {}",
+                    reply.status,
+                    reply.lines.join(" ")
+                );
+                return Err(io::Error::other(msg));
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// Asks Tor for the accepted authentication methods. Tor answers this once
+    /// before authentication, later calls return the first reply.
+    pub async fn protocol_info(&mut self) -> io::Result<ProtocolInfo> {
+        if let Some(ref info) = self.protocol_info {
+            return Ok(info.clone());
+        }
+        let reply = self.command("PROTOCOLINFO 1").await?;
+        let mut info = ProtocolInfo {
+            auth_methods: Vec::new(),
+            cookie_file: None,
+            tor_version: None,
+        };
+        for line in &reply.lines {
+            if let Some(rest) = line.strip_prefix("AUTH ") {
+                for (key, value) in key_values(rest)? {
+                    match key.as_str() {
+                        "METHODS" => {
+                            info.auth_methods = value.split(',').map(String::from).collect()
+                        }
+                        "COOKIEFILE" => info.cookie_file = Some(value.into()),
+                        _ => {}
+                    }
+                }
+            } else if let Some(rest) = line.strip_prefix("VERSION ") {
+                info.tor_version = key_values(rest)?
+                    .into_iter()
+                    .find(|(key, _)| key == "Tor")
+                    .map(|(_, value)| value);
+            }
+        }
+        self.protocol_info = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Authenticates with a method Tor offers, preferring no authentication,
+    /// then `password` if given, then the cookie file.
+    pub async fn authenticate(&mut self, password: Option<&str>) -> io::Result<()> {
+        let ProtocolInfo {
+            auth_methods,
+            cookie_file,
+            ..
+        } = self.protocol_info().await?;
+        let offers = |method: &str| auth_methods.iter().any(|m| m == method);
+
+        let auth = match (password, cookie_file) {
+            _ if offers("NULL") => ControlAuth::Null,
+            (Some(password), _) if offers("HASHEDPASSWORD") => {
+                ControlAuth::HashedPassword(password.to_string())
+            }
+            (_, Some(file)) if offers("SAFECOOKIE") => ControlAuth::SafeCookie(file),
+            (_, Some(file)) if offers("COOKIE") => ControlAuth::Cookie(file),
+            _ => {
+                let msg = format!(
+                    "no supported authentication method, Tor offers {}",
+                    auth_methods.join(",")
+                );
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+            }
+        };
+        self.authenticate_with(&auth).await
+    }
+
+    /// Authenticates with the given method.
+    pub async fn authenticate_with(&mut self, auth: &ControlAuth) -> io::Result<()> {
+        let command = match auth {
+            ControlAuth::Null => "AUTHENTICATE".to_string(),
+            ControlAuth::HashedPassword(password) => {
+                format!("AUTHENTICATE {}", quote(password))
+            }
+            ControlAuth::Cookie(file) => {
+                format!("AUTHENTICATE {}", HEXUPPER.encode(&read_cookie(file)?))
+            }
+            ControlAuth::SafeCookie(file) => {
+                let cookie = read_cookie(file)?;
+                let hash = self.auth_challenge(&cookie).await?;
+                format!("AUTHENTICATE {}", HEXUPPER.encode(&hash))
+            }
+        };
+        self.command(&command).await?;
+        Ok(())
+    }
+
+    /// Runs the `SAFECOOKIE` challenge, returns the hash to authenticate with.
+    async fn auth_challenge(&mut self, cookie: &[u8]) -> io::Result<Vec<u8>> {
+        let client_nonce = rand::random::<[u8; 32]>();
+        let command = format!(
+            "AUTHCHALLENGE SAFECOOKIE {}",
+            HEXUPPER.encode(&client_nonce)
+        );
+        let reply = self.command(&command).await?;
+
+        let mut server_hash = None;
+        let mut server_nonce = None;
+        let rest = reply.lines[0]
+            .strip_prefix("AUTHCHALLENGE ")
+            .unwrap_or_default();
+        for (key, value) in key_values(rest)? {
+            let value = HEXLOWER_PERMISSIVE
+                .decode(value.as_bytes())
+                .map_err(|_| invalid_data(format!("invalid hex in {}", key)))?;
+            match key.as_str() {
+                "SERVERHASH" => server_hash = Some(value),
+                "SERVERNONCE" => server_nonce = Some(value),
+                _ => {}
+            }
+        }
+        let (server_hash, server_nonce) = match (server_hash, server_nonce) {
+            (Some(hash), Some(nonce)) => (hash, nonce),
+            _ => return Err(invalid_data("AUTHCHALLENGE reply without hash or nonce")),
+        };
+
+        let message = [cookie, &client_nonce[..], &server_nonce[..]].concat();
+        if hmac_sha256(SERVER_HASH_KEY, &message) != server_hash {
+            let msg = "Tor does not know the cookie, wrong cookie file?";
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+        }
+        Ok(hmac_sha256(CLIENT_HASH_KEY, &message))
+    }
+
+    /// Subscribes to the given asynchronous events e.g., `CIRC` and
+    /// `STATUS_CLIENT`, replacing the previous subscription.
+    pub async fn set_events(&mut self, events: &[&str]) -> io::Result<()> {
+        let command = format!("SETEVENTS {}", events.join(" "));
+        self.command(command.trim_end()).await?;
+        Ok(())
+    }
+
+    /// Waits for the next asynchronous event.
+    pub async fn next_event(&mut self) -> io::Result<ControlReply> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        let reply = self.read_reply().await?;
+        if reply.status != EVENT_STATUS {
+            return Err(invalid_data(format!("unexpected reply {}", reply.status)));
+        }
+        Ok(reply)
+    }
+
+    /// Creates an onion service forwarding `virtual_port` to `target`, returns
+    /// its onion address. The service lives as long as this connection.
+    pub async fn add_onion(
+        &mut self,
+        virtual_port: u16,
+        target: SocketAddr,
+    ) -> io::Result<Multiaddr> {
+        let command = format!(
+            "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port={},{}",
+            virtual_port, target
+        );
+        let reply = self.command(&command).await?;
+        let service_id = reply
+            .lines
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .ok_or_else(|| invalid_data("ADD_ONION reply without ServiceID"))?;
+
+        format!("/onion3/{}:{}", service_id, virtual_port)
+            .parse()
+            .map_err(|_| invalid_data(format!("invalid ServiceID {}", service_id)))
+    }
+
+    /// Reads a complete, possibly multi-line, reply.
+    async fn read_reply(&mut self) -> io::Result<ControlReply> {
+        let mut status = None;
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line.len() < 4 || !line.is_char_boundary(3) || !line.is_char_boundary(4) {
+                return Err(invalid_data(format!("invalid reply line {:?}", line)));
+            }
+            let code = line[..3]
+                .parse::<u16>()
+                .map_err(|_| invalid_data(format!("invalid status in {:?}", line)))?;
+            if *status.get_or_insert(code) != code {
+                return Err(invalid_data(format!("status changed in {:?}", line)));
+            }
+
+            let mut text = line[4..].to_string();
+            match &line[3..4] {
+                " " => {
+                    lines.push(text);
+                    return Ok(ControlReply {
+                        status: code,
+                        lines,
+                    });
+                }
+                "-" => lines.push(text),
+                "+" => {
+                    // A data block follows, ending with a line holding a
+                    // single dot. Leading dots of data lines are doubled.
+                    loop {
+                        let data = self.read_line().await?;
+                        if data == "." {
+                            break;
+                        }
+                        text.push('\n');
+                        text.push_str(data.strip_prefix('.').unwrap_or(&data));
+                    }
+                    lines.push(text);
+                }
+                _ => return Err(invalid_data(format!("invalid reply line {:?}", line))),
+            }
+        }
+    }
+
+    /// Reads a line without its line ending.
+    async fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+        line.truncate(len);
+        Ok(line)
+    }
+}
+
+/// Splits `KEY=VALUE` pairs separated by spaces, values may be quoted strings.
+fn key_values(s: &str) -> io::Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let eq = rest
+            .find('=')
+            .ok_or_else(|| invalid_data(format!("expected KEY=VALUE in {:?}", s)))?;
+        let key = rest[..eq].to_string();
+        rest = &rest[eq + 1..];
+
+        let mut value = String::new();
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => value.push(c),
+                        None => return Err(invalid_data("unterminated escape")),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => return Err(invalid_data("unterminated quoted string")),
+                }
+            };
+            rest = &quoted[end + 1..];
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            value.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+        pairs.push((key, value));
+        rest = rest.trim_start();
+    }
+    Ok(pairs)
+}
+
+/// Quotes `s` as a control port quoted string, control characters are escaped
+/// so that they can not end the command line.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\r' => quoted.push_str("\\r"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\{:03o}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn read_cookie(file: &Path) -> io::Result<Vec<u8>> {
+    let cookie = fs::read(file)?;
+    if cookie.len() != COOKIE_LEN {
+        let msg = format!("cookie file {} has wrong length", file.display());
+        return Err(invalid_data(msg));
+    }
+    Ok(cookie)
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Accepts a control connection and checks each command against the
+    /// script, answering with the scripted reply.
+    async fn mock_controller(script: Vec<(&'static str, String)>) -> ControlAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            for (command, reply) in script {
+                assert_eq!(read_command(&mut sock).await, command);
+                sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        ControlAddr::Socket(addr)
+    }
+
+    async fn read_command(sock: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        sock.read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn recognises_new_onion_service_address() {
+        let addr = new_onion_service(80);
+        assert_eq!(new_onion_service_port(&addr), Some(80));
+
+        let onion = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:80";
+        assert_eq!(new_onion_service_port(&onion.parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_password_from_protocol_info() {
+        let protocol_info = "250-PROTOCOLINFO 1\r\n\
+             250-AUTH METHODS=COOKIE,HASHEDPASSWORD COOKIEFILE=\"/run/tor/a \\\"b\\\"\"\r\n\
+             250-VERSION Tor=\"0.4.5.6\"\r\n\
+             250 OK\r\n";
+        let addr = mock_controller(vec![
+            ("PROTOCOLINFO 1", protocol_info.to_string()),
+            (r#"AUTHENTICATE "p\"w\r\n""#, "250 OK\r\n".to_string()),
+        ])
+        .await;
+
+        let mut control = TorControl::connect(&addr).await.unwrap();
+        let info = control.protocol_info().await.unwrap();
+        assert_eq!(info.auth_methods, vec!["COOKIE", "HASHEDPASSWORD"]);
+        assert_eq!(info.cookie_file, Some(PathBuf::from("/run/tor/a \"b\"")));
+        assert_eq!(info.tor_version.as_deref(), Some("0.4.5.6"));
+
+        control.authenticate(Some("p\"w\r\n")).await.unwrap();
+    }
+
+    #[test]
+    fn password_is_redacted_in_debug_output() {
+        let auth = ControlAuth::HashedPassword("hunter2".to_string());
+        let debug = format!("{:?}", auth);
+
+        assert!(!debug.contains("hunter2"));
+        assert_eq!(
+            format!("{:?}", ControlAuth::Cookie("/run/tor/cookie".into())),
+            r#"Cookie("/run/tor/cookie")"#
+        );
+    }
+
+    #[test]
+    fn quotes_control_characters() {
+        assert_eq!(quote(r#"a\"b"#), r#""a\\\"b""#);
+        assert_eq!(quote("a\r\n\tb"), r#""a\r\n\tb""#);
+        assert_eq!(quote("a\0\x1b\x7f"), r#""a\000\033\177""#);
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_safe_cookie() {
+        let cookie = [0x42; COOKIE_LEN];
+        let cookie_file =
+            std::env::temp_dir().join(format!("control-auth-cookie-{}", rand::random::<u64>()));
+        fs::write(&cookie_file, cookie).unwrap();
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = ControlAddr::Socket(listener.local_addr().unwrap());
+        let controller = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            let command = read_command(&mut sock).await;
+            let client_nonce = command.strip_prefix("AUTHCHALLENGE SAFECOOKIE ").unwrap();
+            let client_nonce = HEXLOWER_PERMISSIVE.decode(client_nonce.as_bytes()).unwrap();
+
+            let server_nonce = [0x17; 32];
+            let message = [&cookie[..], &client_nonce[..], &server_nonce[..]].concat();
+            let reply = format!(
+                "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                HEXUPPER.encode(&hmac_sha256(SERVER_HASH_KEY, &message)),
+                HEXUPPER.encode(&server_nonce)
+            );
+            sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+
+            let expected = HEXUPPER.encode(&hmac_sha256(CLIENT_HASH_KEY, &message));
+            assert_eq!(
+                read_command(&mut sock).await,
+                format!("AUTHENTICATE {}", expected)
+            );
+            sock.get_mut().write_all(b"250 OK\r\n").await.unwrap();
+        });
+
+        let mut control = TorControl::connect(&addr).await.unwrap();
+        let auth = ControlAuth::SafeCookie(cookie_file.clone());
+        control.authenticate_with(&auth).await.unwrap();
+
+        controller.await.unwrap();
+        fs::remove_file(cookie_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn parses_data_replies_and_keeps_events() {
+        let addr = mock_controller(vec![
+            ("AUTHENTICATE", "250 OK\r\n".to_string()),
+            (
+                "GETINFO config-text",
+                "650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100\r\n\
+                 250+config-text=\r\n\
+                 SocksPort 9050\r\n\
+                 ..hidden\r\n\
+                 .\r\n\
+                 250 OK\r\n"
+                    .to_string(),
+            ),
+            ("GETINFO version", "551 Internal error\r\n".to_string()),
+        ])
+        .await;
+
+        let mut control = TorControl::connect(&addr).await.unwrap();
+        control.authenticate_with(&ControlAuth::Null).await.unwrap();
+
+        let reply = control.command("GETINFO config-text").await.unwrap();
+        assert_eq!(
+            reply.lines,
+            vec!["config-text=\nSocksPort 9050\n.hidden", "OK"]
+        );
+        let event = control.next_event().await.unwrap();
+        assert_eq!(event.status, 650);
+        assert_eq!(
+            event.lines,
+            vec!["STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100"]
+        );
+
+        let err = control.command("GETINFO version").await.unwrap_err();
+        assert_eq!(err.to_string(), "control port replied 551 Internal error");
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Errors of the transport.
+
+use crate::{routing::Destination, socks::ReplyError};
+use libp2p::core::Multiaddr;
+use std::{error, fmt, io, time::Duration};
+
+/// Error of the transport, records in which phase of the dial it happened.
+///
+/// Converts into `io::Error`, keeping the `io::ErrorKind` of the underlying
+/// error, for users that only care about I/O errors.
+#[derive(Debug)]
+pub enum Socks5TransportError {
+    /// Opening the connection to the proxy failed.
+    ProxyConnect(io::Error),
+    /// Negotiating the authentication method with the proxy failed.
+    MethodNegotiation(io::Error),
+    /// Authenticating with the proxy failed.
+    Authentication(io::Error),
+    /// The proxy failed our `CONNECT` request or sent an invalid reply.
+    ConnectReply(io::Error),
+    /// Applying the socket options to the connection failed.
+    SocketConfig(io::Error),
+    /// The routing policy rejects dialing this class of destination.
+    RoutingRejected(Destination),
+    /// The onion address has a wrong checksum or version, most likely a typo.
+    InvalidOnionAddress(Multiaddr, &'static str),
+    /// The destination filter rejects dialing this address, with its reason.
+    DestinationRejected(Multiaddr, String),
+    /// The proxy could not be reached within the proxy connect timeout.
+    ProxyConnectTimeout(Duration),
+    /// Negotiating the auth method and authenticating did not finish within
+    /// the negotiation timeout.
+    NegotiationTimeout(Duration),
+    /// The proxy did not reply to our `CONNECT` request within the connect
+    /// timeout.
+    ConnectReplyTimeout(Duration),
+    /// Creating an onion service through the Tor control port failed.
+    Control(io::Error),
+    /// Any other I/O error e.g., while listening or dialing directly.
+    Io(io::Error),
+}
+
+impl Socks5TransportError {
+    /// The reply of the proxy if it failed our `CONNECT` request.
+    pub fn reply(&self) -> Option<ReplyError> {
+        match self {
+            Socks5TransportError::ConnectReply(e) => e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<ReplyError>())
+                .copied(),
+            _ => None,
+        }
+    }
+
+    /// The reply code of the proxy if it failed our `CONNECT` request.
+    pub fn reply_code(&self) -> Option<u8> {
+        self.reply().map(|reply| reply.code())
+    }
+
+    /// Whether dialing again, later, may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Socks5TransportError::ProxyConnect(e) => !is_misconfiguration(e),
+            Socks5TransportError::ProxyConnectTimeout(_)
+            | Socks5TransportError::NegotiationTimeout(_)
+            | Socks5TransportError::ConnectReplyTimeout(_) => true,
+            Socks5TransportError::Authentication(_)
+            | Socks5TransportError::SocketConfig(_)
+            | Socks5TransportError::RoutingRejected(_)
+            | Socks5TransportError::InvalidOnionAddress(..)
+            | Socks5TransportError::DestinationRejected(..) => false,
+            Socks5TransportError::ConnectReply(e) => match self.reply() {
+                Some(reply) => reply.is_retryable(),
+                None => is_transient(e),
+            },
+            Socks5TransportError::MethodNegotiation(e)
+            | Socks5TransportError::Control(e)
+            | Socks5TransportError::Io(e) => is_transient(e),
+        }
+    }
+
+    /// The underlying I/O error, if any.
+    fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Socks5TransportError::ProxyConnect(e)
+            | Socks5TransportError::MethodNegotiation(e)
+            | Socks5TransportError::Authentication(e)
+            | Socks5TransportError::ConnectReply(e)
+            | Socks5TransportError::SocketConfig(e)
+            | Socks5TransportError::Control(e)
+            | Socks5TransportError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// The `io::ErrorKind` used when converting into an `io::Error`.
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Socks5TransportError::RoutingRejected(_)
+            | Socks5TransportError::DestinationRejected(..) => io::ErrorKind::PermissionDenied,
+            Socks5TransportError::InvalidOnionAddress(..) => io::ErrorKind::InvalidInput,
+            Socks5TransportError::ProxyConnectTimeout(_)
+            | Socks5TransportError::NegotiationTimeout(_)
+            | Socks5TransportError::ConnectReplyTimeout(_) => io::ErrorKind::TimedOut,
+            e => e.io_error().map_or(io::ErrorKind::Other, io::Error::kind),
+        }
+    }
+}
+
+/// Whether the proxy connect error points at the configuration, e.g. a Unix
+/// socket path that does not exist, rather than at a proxy that is not up.
+fn is_misconfiguration(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::AddrNotAvailable
+    )
+}
+
+/// Whether the I/O error is likely to go away by itself.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+    )
+}
+
+impl fmt::Display for Socks5TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Socks5TransportError::ProxyConnect(e) => write!(f, "connecting to proxy: {}", e),
+            Socks5TransportError::MethodNegotiation(e) => {
+                write!(f, "negotiating auth method with proxy: {}", e)
+            }
+            Socks5TransportError::Authentication(e) => {
+                write!(f, "authenticating with proxy: {}", e)
+            }
+            Socks5TransportError::ConnectReply(e) => write!(f, "proxy CONNECT failed: {}", e),
+            Socks5TransportError::SocketConfig(e) => write!(f, "configuring socket: {}", e),
+            Socks5TransportError::RoutingRejected(destination) => write!(
+                f,
+                "dial to {:?} destination rejected by routing policy",
+                destination
+            ),
+            Socks5TransportError::InvalidOnionAddress(addr, reason) => {
+                write!(f, "invalid onion address {}: {}", addr, reason)
+            }
+            Socks5TransportError::DestinationRejected(addr, reason) => {
+                write!(
+                    f,
+                    "dial to {} rejected by destination filter: {}",
+                    addr, reason
+                )
+            }
+            Socks5TransportError::ProxyConnectTimeout(after) => {
+                write!(f, "connecting to proxy timed out after {:?}", after)
+            }
+            Socks5TransportError::NegotiationTimeout(after) => {
+                write!(f, "negotiating with proxy timed out after {:?}", after)
+            }
+            Socks5TransportError::ConnectReplyTimeout(after) => {
+                write!(f, "proxy CONNECT timed out after {:?}", after)
+            }
+            Socks5TransportError::Control(e) => write!(f, "Tor control port: {}", e),
+            Socks5TransportError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Socks5TransportError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io_error().map(|e| e as &(dyn error::Error + 'static))
+    }
+}
+
+impl From<io::Error> for Socks5TransportError {
+    fn from(e: io::Error) -> Self {
+        Socks5TransportError::Io(e)
+    }
+}
+
+impl From<Socks5TransportError> for io::Error {
+    fn from(e: Socks5TransportError) -> Self {
+        match e {
+            Socks5TransportError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_reply_carries_reply_code() {
+        let inner = io::Error::new(io::ErrorKind::NotFound, ReplyError::OnionDescriptorNotFound);
+        let err = Socks5TransportError::ConnectReply(inner);
+
+        assert_eq!(err.reply(), Some(ReplyError::OnionDescriptorNotFound));
+        assert_eq!(err.reply_code(), Some(0xf0));
+        assert!(err.is_retryable());
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn authentication_failure_is_not_retryable() {
+        let inner = io::Error::new(io::ErrorKind::ConnectionRefused, "bad password");
+        let err = Socks5TransportError::Authentication(inner);
+
+        assert!(!err.is_retryable());
+        assert_eq!(err.reply(), None);
+    }
+
+    #[test]
+    fn proxy_connect_retryability_depends_on_kind() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(Socks5TransportError::ProxyConnect(refused).is_retryable());
+
+        let missing_socket = io::Error::from(io::ErrorKind::NotFound);
+        assert!(!Socks5TransportError::ProxyConnect(missing_socket).is_retryable());
+    }
+
+    #[test]
+    fn timeouts_convert_to_timed_out() {
+        let err = Socks5TransportError::NegotiationTimeout(Duration::from_secs(5));
+
+        assert!(err.is_retryable());
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Destination filter deciding whether a dial is allowed at all, it runs
+//! before any traffic is sent to the proxy.
+
+use data_encoding::BASE32;
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use std::{collections::HashSet, fmt, sync::Arc};
+
+/// Decides whether a multiaddr may be dialed.
+pub trait DestinationFilter: Send + Sync {
+    /// Returns the reason for rejecting a dial to `addr`, as given to `dial`.
+    fn check(&self, addr: &Multiaddr) -> Result<(), String>;
+}
+
+impl<F> DestinationFilter for F
+where
+    F: Fn(&Multiaddr) -> Result<(), String> + Send + Sync,
+{
+    fn check(&self, addr: &Multiaddr) -> Result<(), String> {
+        self(addr)
+    }
+}
+
+/// A destination filter shared between clones of the configuration.
+#[derive(Clone)]
+pub(crate) struct SharedFilter(Arc<dyn DestinationFilter>);
+
+impl SharedFilter {
+    pub(crate) fn new(filter: impl DestinationFilter + 'static) -> Self {
+        SharedFilter(Arc::new(filter))
+    }
+
+    pub(crate) fn check(&self, addr: &Multiaddr) -> Result<(), String> {
+        self.0.check(addr)
+    }
+}
+
+impl fmt::Debug for SharedFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedFilter")
+    }
+}
+
+/// Destination filter for common policies, allows everything by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DestinationPolicy {
+    onion3_only: bool,
+    deny_onion_v2: bool,
+    ports: Option<HashSet<u16>>,
+    denied_onions: HashSet<String>,
+}
+
+impl DestinationPolicy {
+    /// Only allows onion v3 addresses.
+    pub fn onion3_only(mut self) -> Self {
+        self.onion3_only = true;
+        self
+    }
+
+    /// Rejects onion v2 addresses, Tor no longer supports them.
+    pub fn deny_onion_v2(mut self) -> Self {
+        self.deny_onion_v2 = true;
+        self
+    }
+
+    /// Only allows dialing these ports.
+    pub fn allow_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.ports = Some(ports.into_iter().collect());
+        self
+    }
+
+    /// Rejects an onion service, given as its address with or without the
+    /// `.onion` suffix.
+    pub fn deny_onion(mut self, host: &str) -> Self {
+        let host = host.to_ascii_lowercase();
+        let host = host.strip_suffix('.').unwrap_or(&host);
+        let host = host.trim_end_matches(".onion");
+        self.denied_onions.insert(host.to_string());
+        self
+    }
+}
+
+impl DestinationFilter for DestinationPolicy {
+    fn check(&self, addr: &Multiaddr) -> Result<(), String> {
+        let mut onion = None;
+        let mut port = None;
+        let mut is_v2 = false;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::Onion(hash, p) => {
+                    onion = Some(BASE32.encode(hash.as_ref()).to_lowercase());
+                    port = Some(p);
+                    is_v2 = true;
+                }
+                Protocol::Onion3(addr) => {
+                    onion = Some(BASE32.encode(addr.hash()).to_lowercase());
+                    port = Some(addr.port());
+                }
+                // Onion services can also be dialed by name, v3 names have 56
+                // characters and v2 names 16.
+                Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                    let name = name.to_ascii_lowercase();
+                    let name = name.strip_suffix('.').unwrap_or(&name);
+                    if let Some(host) = name.strip_suffix(".onion") {
+                        let host = host.rsplit('.').next().unwrap_or(host);
+                        is_v2 = host.len() == 16;
+                        if host.len() == 56 || is_v2 {
+                            onion = Some(host.to_string());
+                        }
+                    }
+                }
+                Protocol::Tcp(p) => port = Some(p),
+                _ => {}
+            }
+        }
+
+        if is_v2 && (self.deny_onion_v2 || self.onion3_only) {
+            return Err("onion v2 addresses are not allowed".to_string());
+        }
+        if self.onion3_only && onion.is_none() {
+            return Err("only onion v3 addresses are allowed".to_string());
+        }
+        if let (Some(ports), Some(port)) = (&self.ports, port) {
+            if !ports.contains(&port) {
+                return Err(format!("port {} is not allowed", port));
+            }
+        }
+        if let Some(onion) = onion {
+            if self.denied_onions.contains(&onion) {
+                return Err(format!("{}.onion is denied", onion));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION3: &str = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234";
+
+    fn check(policy: &DestinationPolicy, addr: &str) -> Result<(), String> {
+        policy.check(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn onion3_only_rejects_everything_else() {
+        let policy = DestinationPolicy::default().onion3_only();
+
+        assert!(check(&policy, ONION3).is_ok());
+        assert!(check(&policy, "/onion/aaimaq4ygg2iegci:80").is_err());
+        assert!(check(&policy, "/ip4/203.0.113.7/tcp/80").is_err());
+        assert!(check(&policy, "/dns/example.com/tcp/443").is_err());
+    }
+
+    #[test]
+    fn can_restrict_ports_and_deny_onions() {
+        let policy = DestinationPolicy::default()
+            .allow_ports(vec![443, 1234])
+            .deny_onion("VWW6YBAL4BD7SZMGNCYRUUCPGFKQAHZDDI37KTCEO3AH7NGMCOPNPYYD.onion");
+
+        assert!(check(&policy, "/dns/example.com/tcp/443").is_ok());
+        assert!(check(&policy, "/dns/example.com/tcp/80").is_err());
+        assert!(check(&policy, ONION3).is_err());
+    }
+
+    #[test]
+    fn treats_onion_names_as_onions() {
+        let v3 = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+        let policy = DestinationPolicy::default().onion3_only().deny_onion(v3);
+
+        let addr = format!("/dns/{}.onion/tcp/1234", v3.to_uppercase());
+        assert_eq!(
+            check(&policy, &addr),
+            Err(format!("{}.onion is denied", v3))
+        );
+        let addr = format!("/dns/{}.onion./tcp/1234", v3);
+        assert_eq!(
+            check(&policy, &addr),
+            Err(format!("{}.onion is denied", v3))
+        );
+        let addr = "/dns4/ltgw3ssugb6mm5q2l4bjxpvxf5kdfhufjpn4vx5zrv56qpfzmfeuzxad.onion/tcp/1234";
+        assert!(check(&policy, addr).is_ok());
+        assert_eq!(
+            check(&policy, "/dns6/aaimaq4ygg2iegci.onion/tcp/80"),
+            Err("onion v2 addresses are not allowed".to_string())
+        );
+        assert!(check(
+            &DestinationPolicy::default(),
+            "/dns/aaimaq4ygg2iegci.onion/tcp/80"
+        )
+        .is_ok());
+    }
+}
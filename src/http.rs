@@ -0,0 +1,137 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Client side of an HTTP `CONNECT` tunnel (RFC 7231 section 4.3.6) with
+//! optional Basic proxy authentication (RFC 7617).
+
+use crate::{socks::TargetAddr, Credentials};
+use data_encoding::BASE64;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum size of the response head we are willing to read.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// Asks the HTTP proxy on `stream` to open a tunnel to `target`. On success the
+/// stream is tunneled through to the target.
+pub async fn connect<S>(
+    stream: &mut S,
+    target: &TargetAddr,
+    credentials: Option<&Credentials>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut req = format!(
+        "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n",
+        target = target
+    );
+    if let Some(creds) = credentials {
+        let token = format!("{}:{}", creds.username, creds.password);
+        req.push_str("Proxy-Authorization: Basic ");
+        req.push_str(&BASE64.encode(token.as_bytes()));
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    let head = read_response_head(stream).await?;
+    let status_line = head.lines().next().unwrap_or_default();
+    match parse_status(status_line) {
+        Some(code) if (200..300).contains(&code) => Ok(()),
+        Some(407) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("proxy authentication failed: {}", status_line),
+        )),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("proxy refused CONNECT: {}", status_line),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid HTTP status line: {:?}", status_line),
+        )),
+    }
+}
+
+/// Reads the response head up to and including the empty line. We read byte by
+/// byte so that no tunneled data is consumed.
+async fn read_response_head<S>(stream: &mut S) -> io::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP response head too large",
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    String::from_utf8(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parses the status code from a status line e.g., `HTTP/1.1 200 OK`.
+fn parse_status(line: &str) -> Option<u16> {
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks::tests::mock_proxy;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn connect_with_basic_auth() {
+        let addr = mock_proxy(vec![(
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+              Proxy-Authorization: Basic Ym9iOnB3ZA==\r\n\r\n",
+            b"HTTP/1.1 200 Connection established\r\n\r\nhello",
+        )])
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let target = TargetAddr::Domain("example.com".to_string(), 443);
+        let creds = Credentials::new("bob", "pwd");
+        connect(&mut stream, &target, Some(&creds)).await.unwrap();
+
+        let mut tunneled = [0u8; 5];
+        stream.read_exact(&mut tunneled).await.unwrap();
+        assert_eq!(&tunneled, b"hello");
+    }
+
+    #[test]
+    fn can_parse_status_line() {
+        assert_eq!(
+            parse_status("HTTP/1.1 407 Proxy Authentication Required"),
+            Some(407)
+        );
+        assert_eq!(parse_status("HTTP/1.0 200"), Some(200));
+        assert_eq!(parse_status("SSH-2.0-OpenSSH"), None);
+    }
+}
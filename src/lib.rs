@@ -25,36 +25,68 @@
 //! Copied from github.com/libp2p/rust-libp2p/transports/tcp/lib.rs with
 //! features = "tcp-tokio". Modified by Tobin C. Harding <me@tobin.cc>
 
-use data_encoding::BASE32;
+use data_encoding::{BASE32, HEXLOWER};
 use futures::{
-    future::{self, Ready},
+    future::{self, Either, Ready},
     prelude::*,
 };
 use futures_timer::Delay;
-use get_if_addrs::{get_if_addrs, IfAddr};
+use if_addrs::{get_if_addrs, IfAddr};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use libp2p::core::{
-    multiaddr::{Multiaddr, Protocol},
+    multiaddr::{Multiaddr, Onion3Addr, Protocol},
     transport::{ListenerEvent, TransportError},
-    Transport,
+    PeerId, Transport,
 };
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use sha3::{Digest, Sha3_256};
 use socket2::{Domain, Socket, Type};
 use std::{
     collections::{HashMap, VecDeque},
     convert::TryFrom,
-    io, iter,
+    fmt, io, iter,
     net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
     pin::Pin,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
+#[cfg(unix)]
+use std::{os::unix::fs::FileTypeExt, path::PathBuf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+mod control;
+mod error;
+mod filter;
+mod http;
+mod pool;
+mod probe;
+mod resolve;
+mod routing;
+mod socks;
+
+pub use control::{
+    new_onion_service, ControlAddr, ControlAuth, ControlReply, ProtocolInfo, TorControl,
+};
+use control::{new_onion_service_port, ControlPassword};
+pub use error::Socks5TransportError;
+use filter::SharedFilter;
+pub use filter::{DestinationFilter, DestinationPolicy};
+pub use pool::SelectionStrategy;
+use pool::{Outstanding, ProxyPool};
+pub use probe::ProbeReport;
+pub use resolve::{TorDnsConfig, TorDnsError};
+pub use routing::{Destination, Route, RoutingPolicy};
+use socks::TargetAddr;
+pub use socks::{AuthMethod, ReplyError};
 
 /// Default port for the Tor SOCKS5 proxy.
 const DEFAULT_SOCKS_PORT: u16 = 9050;
 
+/// Version byte of onion v3 addresses.
+const ONION3_VERSION: u8 = 0x03;
+
 /// Represents the configuration for a TCP/IP transport capability for libp2p.
 ///
 /// The TCP sockets created by libp2p will need to be progressed by running the
@@ -69,21 +101,62 @@ pub struct Socks5TokioTcpConfig {
     ttl: Option<u32>,
     /// `TCP_NODELAY` to set for opened sockets, or `None` to keep default.
     nodelay: Option<bool>,
-    /// Map of Multiaddr to port number for local socket.
-    onion_map: HashMap<Multiaddr, u16>,
-    /// Tor SOCKS5 proxy port number.
-    socks_port: u16,
+    /// Map of onion Multiaddr to the local target Tor forwards it to.
+    onion_map: HashMap<Multiaddr, LocalTarget>,
+    /// Whether onion listeners also report their loopback bind address.
+    announce_loopback: bool,
+    /// Tor control port used to create new onion services, or `None`.
+    control_port: Option<ControlAddr>,
+    /// Password for the control port, used if Tor asks for one.
+    control_password: Option<ControlPassword>,
+    /// Proxy endpoints, the first proxy of a dial is one of these.
+    proxies: ProxyPool,
+    /// Protocol spoken with the proxy.
+    protocol: ProxyProtocol,
+    /// Further proxies to tunnel through, in order, after the first one.
+    chain: Vec<ProxyHop>,
+    /// Username/password used to authenticate with the SOCKS5 proxy, or `None`
+    /// to only offer the "no authentication" method.
+    credentials: Option<Credentials>,
+    /// Tor stream isolation policy, or `None` to let Tor pick circuits.
+    isolation: Option<StreamIsolation>,
+    /// Decides which dials go through the proxy.
+    routing: RoutingPolicy,
+    /// Decides which dials are allowed at all, or `None` to allow all.
+    filter: Option<SharedFilter>,
+    /// How long to wait for the connection to the proxy, or `None` to wait
+    /// forever.
+    proxy_connect_timeout: Option<Duration>,
+    /// How long to wait for auth method negotiation and authentication, per
+    /// proxy, or `None` to wait forever.
+    negotiation_timeout: Option<Duration>,
+    /// How long to wait for the reply to a `CONNECT` request, per proxy, or
+    /// `None` to wait forever.
+    connect_timeout: Option<Duration>,
 }
 
 impl Socks5TokioTcpConfig {
-    /// Creates a new configuration object for TCP/IP.
+    /// Creates a new configuration object for TCP/IP using a SOCKS5 proxy
+    /// listening on localhost at `socks_port`.
     pub fn new(socks_port: u16) -> Self {
         Self {
             sleep_on_error: Duration::from_millis(100),
             ttl: None,
             nodelay: None,
             onion_map: HashMap::new(),
-            socks_port,
+            announce_loopback: false,
+            control_port: None,
+            control_password: None,
+            proxies: ProxyPool::new(vec![ProxyAddr::localhost(socks_port)]),
+            protocol: ProxyProtocol::default(),
+            chain: Vec::new(),
+            credentials: None,
+            isolation: None,
+            routing: RoutingPolicy::default(),
+            filter: None,
+            proxy_connect_timeout: None,
+            negotiation_timeout: None,
+            connect_timeout: None,
         }
     }
 
@@ -99,30 +172,440 @@ impl Socks5TokioTcpConfig {
         self
     }
 
-    /// Sets the map for onion address -> local socket port number.
+    /// Sets the map for onion address -> local socket port number on
+    /// `127.0.0.1`.
     pub fn onion_map(mut self, value: HashMap<Multiaddr, u16>) -> Self {
-        self.onion_map = value;
+        self.onion_map = value
+            .into_iter()
+            .map(|(onion, port)| (onion, port.into()))
+            .collect();
+        self
+    }
+
+    /// Adds onion addresses mapped to any local target, as configured with
+    /// Tor's `HiddenServicePort`, to the onion map. Call it after
+    /// `onion_map`, which replaces the whole map.
+    pub fn onion_targets(mut self, value: HashMap<Multiaddr, LocalTarget>) -> Self {
+        self.onion_map.extend(value);
+        self
+    }
+
+    /// Sets whether a listener on an onion address also reports its loopback
+    /// bind address as a listen address, by default only the onion address is
+    /// reported so that the loopback address is never advertised to peers.
+    pub fn announce_loopback(mut self, value: bool) -> Self {
+        self.announce_loopback = value;
+        self
+    }
+
+    /// Sets the Tor control port, needed to listen on `new_onion_service`
+    /// addresses. Either a socket address or (on Unix) a Unix domain socket.
+    pub fn control_port(mut self, addr: impl Into<ControlAddr>) -> Self {
+        self.control_port = Some(addr.into());
+        self
+    }
+
+    /// Sets the password for Tor's `HashedControlPassword`, without it only
+    /// cookie authentication or none is used with the control port.
+    pub fn control_password(mut self, password: impl Into<String>) -> Self {
+        self.control_password = Some(ControlPassword(password.into()));
         self
     }
 
-    /// Sets the Tor SOCKS5 proxy port number.
+    /// Sets the Tor SOCKS5 proxy port number, the proxy is expected to listen
+    /// on localhost.
     pub fn socks_port(mut self, port: u16) -> Self {
-        self.socks_port = port;
+        self.proxies.set_endpoints(vec![ProxyAddr::localhost(port)]);
+        self
+    }
+
+    /// Sets the address of the proxy.
+    pub fn proxy_addr(mut self, value: impl Into<ProxyAddr>) -> Self {
+        self.proxies.set_endpoints(vec![value.into()]);
+        self
+    }
+
+    /// Sets several equivalent proxies e.g., one per Tor daemon. Which one is
+    /// used for a dial depends on the `proxy_selection()` strategy.
+    ///
+    /// An endpoint that can not be connected to is marked unhealthy, the dial
+    /// moves on to the next endpoint.
+    pub fn proxy_addrs(mut self, values: Vec<ProxyAddr>) -> Self {
+        self.proxies.set_endpoints(values);
+        self
+    }
+
+    /// Sets the strategy to pick a proxy endpoint, defaults to failover.
+    pub fn proxy_selection(mut self, value: SelectionStrategy) -> Self {
+        self.proxies.set_strategy(value);
+        self
+    }
+
+    /// Sets for how long an unhealthy proxy endpoint is only tried after all
+    /// healthy ones, defaults to 30 seconds.
+    pub fn proxy_unhealthy_period(mut self, value: Duration) -> Self {
+        self.proxies.set_unhealthy_period(value);
+        self
+    }
+
+    /// Sets the protocol spoken with the proxy, defaults to SOCKS5.
+    pub fn protocol(mut self, value: ProxyProtocol) -> Self {
+        self.protocol = value;
+        self
+    }
+
+    /// Sets the chain of proxies to tunnel through after the first proxy.
+    ///
+    /// Each hop's `CONNECT` is sent through the tunnel built so far, the
+    /// dialed address is requested from the last hop.
+    pub fn proxy_chain(mut self, hops: Vec<ProxyHop>) -> Self {
+        self.chain = hops;
+        self
+    }
+
+    /// Sets the username/password used to authenticate with the first proxy
+    /// (RFC 1929).
+    pub fn credentials(mut self, value: Credentials) -> Self {
+        self.credentials = Some(value);
+        self
+    }
+
+    /// Sets the Tor stream isolation policy.
+    ///
+    /// The policy produces synthetic SOCKS5 credentials on each dial, these
+    /// are sent to the proxy that connects to the dialed address, the last hop
+    /// of a proxy chain, and take precedence over its own credentials.
+    pub fn stream_isolation(mut self, value: StreamIsolation) -> Self {
+        self.isolation = Some(value);
+        self
+    }
+
+    /// Sets the routing policy, by default every dial goes through the proxy.
+    pub fn routing_policy(mut self, value: RoutingPolicy) -> Self {
+        self.routing = value;
+        self
+    }
+
+    /// Sets the destination filter, it is given every dialed address this
+    /// transport supports before any traffic is sent to the proxy. See
+    /// `DestinationPolicy` for common policies, a closure can be used for
+    /// anything else.
+    pub fn destination_filter(mut self, value: impl DestinationFilter + 'static) -> Self {
+        self.filter = Some(SharedFilter::new(value));
+        self
+    }
+
+    /// Sets how long to wait for the connection to the proxy. A proxy that
+    /// is down usually refuses the connection at once, this catches proxies
+    /// that are unreachable or hung.
+    pub fn proxy_connect_timeout(mut self, value: Duration) -> Self {
+        self.proxy_connect_timeout = Some(value);
+        self
+    }
+
+    /// Sets how long to wait for the SOCKS5 auth method negotiation and
+    /// authentication with each proxy.
+    pub fn negotiation_timeout(mut self, value: Duration) -> Self {
+        self.negotiation_timeout = Some(value);
+        self
+    }
+
+    /// Sets how long to wait for each proxy to reply to our `CONNECT` request.
+    /// For the last proxy this includes reaching the remote, building an onion
+    /// service rendezvous can take a long time.
+    pub fn connect_timeout(mut self, value: Duration) -> Self {
+        self.connect_timeout = Some(value);
+        self
+    }
+}
+
+impl Socks5TokioTcpConfig {
+    /// Returns the credentials to authenticate with the first proxy for a
+    /// request about `dest`.
+    fn credentials_for(&self, dest: &TargetAddr, peer_id: Option<&PeerId>) -> Option<Credentials> {
+        match self.isolation {
+            Some(ref isolation) => Some(isolation.credentials(dest, peer_id)),
+            None => self.credentials.clone(),
+        }
+    }
+
+    /// Gives a dialed address to the destination filter.
+    fn check_filter(&self, addr: &Multiaddr) -> Result<(), Socks5TransportError> {
+        if let Some(ref filter) = self.filter {
+            if let Err(reason) = filter.check(addr) {
+                info!(
+                    "Dial to {} rejected by destination filter: {}",
+                    addr, reason
+                );
+                return Err(Socks5TransportError::DestinationRejected(
+                    addr.clone(),
+                    reason,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The protocol spoken with the proxy that connects to the dialed address,
+    /// the last hop of a proxy chain.
+    fn final_protocol(&self) -> ProxyProtocol {
+        self.chain.last().map_or(self.protocol, |hop| hop.protocol)
+    }
+
+    /// Whether the proxy can connect to `dest`, SOCKS4 only reaches IPv4
+    /// addresses and SOCKS4a also domain names. Direct dials reach anything.
+    fn can_reach(&self, dest: &TargetAddr) -> bool {
+        if self.routing.route(Destination::of(dest)) == Route::Direct {
+            return true;
+        }
+        match (self.final_protocol(), dest) {
+            (ProxyProtocol::Socks4, TargetAddr::Ip(SocketAddr::V4(_))) => true,
+            (ProxyProtocol::Socks4, _) => false,
+            (ProxyProtocol::Socks4a, TargetAddr::Ip(SocketAddr::V6(_))) => false,
+            _ => true,
+        }
+    }
+
+    /// Returns the route the routing policy picks for `dest`, or an error if
+    /// it rejects it.
+    fn route(&self, dest: &TargetAddr) -> Result<Route, Socks5TransportError> {
+        let destination = Destination::of(dest);
+        let route = self.routing.route(destination);
+        info!(
+            target: routing::LOG_TARGET,
+            "{:?} destination {}: {:?}", destination, dest, route
+        );
+        if route == Route::Reject {
+            return Err(Socks5TransportError::RoutingRejected(destination));
+        }
+        Ok(route)
+    }
+}
+
+/// Tor stream isolation policy.
+///
+/// With `IsolateSOCKSAuth` (on by default) Tor only shares a circuit between
+/// streams that were opened with the same SOCKS5 username/password. Each policy
+/// derives these credentials from a different isolation key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamIsolation {
+    /// One circuit per destination address.
+    PerDestination,
+    /// One circuit per remote peer, as given by a trailing `/p2p/<peer id>` in
+    /// the dialed address. Dials without a peer id get a fresh circuit.
+    PerPeer,
+    /// A fresh circuit for every dial.
+    PerDial,
+    /// One circuit per caller-supplied key.
+    Key(String),
+}
+
+impl StreamIsolation {
+    /// Returns the synthetic SOCKS5 credentials for a dial to `dest`.
+    fn credentials(&self, dest: &TargetAddr, peer_id: Option<&PeerId>) -> Credentials {
+        match (self, peer_id) {
+            (StreamIsolation::PerDestination, _) => {
+                Credentials::new("destination", dest.to_string())
+            }
+            (StreamIsolation::PerPeer, Some(peer_id)) => {
+                Credentials::new("peer", peer_id.to_base58())
+            }
+            (StreamIsolation::PerPeer, None) | (StreamIsolation::PerDial, _) => {
+                Credentials::new("dial", HEXLOWER.encode(&rand::random::<[u8; 16]>()))
+            }
+            (StreamIsolation::Key(key), _) => Credentials::new("key", key.as_str()),
+        }
+    }
+}
+
+/// Protocol spoken with the proxy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// SOCKS5 (RFC 1928), domain names are resolved by the proxy.
+    #[default]
+    Socks5,
+    /// SOCKS4, only IPv4 targets can be dialed.
+    Socks4,
+    /// SOCKS4a, domain names (including `.onion`) are resolved by the proxy.
+    Socks4a,
+    /// HTTP `CONNECT` tunnel, credentials are sent using Basic proxy
+    /// authentication.
+    HttpConnect,
+}
+
+/// A proxy reached through the tunnel of the previous proxy in a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHop {
+    addr: TargetAddr,
+    protocol: ProxyProtocol,
+    credentials: Option<Credentials>,
+}
+
+impl ProxyHop {
+    /// Creates a new hop to the proxy at `host:port`. The host, an IP address
+    /// (IPv6 optionally in brackets) or a domain name, is resolved by the
+    /// previous proxy.
+    pub fn new(host: impl Into<String>, port: u16, protocol: ProxyProtocol) -> Self {
+        let host = host.into();
+        let ip = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(ip) => ip,
+            None => &host,
+        };
+        let addr = match ip.parse::<IpAddr>() {
+            Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+            Err(_) => TargetAddr::Domain(host, port),
+        };
+        Self {
+            addr,
+            protocol,
+            credentials: None,
+        }
+    }
+
+    /// Sets the credentials used to authenticate with this proxy.
+    pub fn credentials(mut self, value: Credentials) -> Self {
+        self.credentials = Some(value);
         self
     }
 }
 
+/// Address of the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyAddr {
+    /// A socket address, IPv4 or IPv6.
+    Socket(SocketAddr),
+    /// A host name and port, the host name is resolved every time we dial.
+    Host(String, u16),
+    /// Path of a Unix domain socket e.g., Tor's `SocksPort unix:/run/tor/socks`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ProxyAddr {
+    /// The proxy listening on localhost at `port`.
+    pub fn localhost(port: u16) -> Self {
+        ProxyAddr::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)))
+    }
+
+    /// Opens a connection to the proxy.
+    async fn connect(&self) -> io::Result<Connection> {
+        let conn = match self {
+            ProxyAddr::Socket(addr) => Connection::Tcp(TcpStream::connect(*addr).await?),
+            ProxyAddr::Host(host, port) => {
+                Connection::Tcp(TcpStream::connect((host.as_str(), *port)).await?)
+            }
+            #[cfg(unix)]
+            ProxyAddr::Unix(path) => Connection::Unix(UnixStream::connect(path).await?),
+        };
+        Ok(conn)
+    }
+}
+
+impl From<SocketAddr> for ProxyAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ProxyAddr::Socket(addr)
+    }
+}
+
+/// Local target Tor forwards connections to an onion service to, this is where
+/// the listener accepts them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalTarget {
+    /// A socket address e.g., `127.0.0.1:7777` or `[::1]:7777`.
+    Socket(SocketAddr),
+    /// Path of a Unix domain socket e.g., for `HiddenServicePort 7
+    /// unix:/run/app/onion.sock`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl LocalTarget {
+    /// The TCP port of the target, `None` for Unix domain sockets.
+    fn port(&self) -> Option<u16> {
+        match self {
+            LocalTarget::Socket(addr) => Some(addr.port()),
+            #[cfg(unix)]
+            LocalTarget::Unix(_) => None,
+        }
+    }
+}
+
+impl From<u16> for LocalTarget {
+    fn from(port: u16) -> Self {
+        LocalTarget::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)))
+    }
+}
+
+impl From<SocketAddr> for LocalTarget {
+    fn from(addr: SocketAddr) -> Self {
+        LocalTarget::Socket(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<PathBuf> for LocalTarget {
+    fn from(path: PathBuf) -> Self {
+        LocalTarget::Unix(path)
+    }
+}
+
+impl fmt::Display for ProxyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyAddr::Socket(addr) => write!(f, "{}", addr),
+            ProxyAddr::Host(host, port) => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            ProxyAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Username/password credentials for the proxy.
+///
+/// The `Debug` implementation redacts both fields so that credentials never end
+/// up in logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    /// Creates new username/password credentials.
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Stream isolation credentials for a proxy speaking `protocol`. SOCKS4
+    /// has no password, the whole isolation key goes into the user id so that
+    /// Tor still keeps the streams apart.
+    fn isolating(&self, protocol: ProxyProtocol) -> Self {
+        match protocol {
+            ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+                Credentials::new(format!("{}:{}", self.username, self.password), "")
+            }
+            ProxyProtocol::Socks5 | ProxyProtocol::HttpConnect => self.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &"<redacted>")
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 impl Default for Socks5TokioTcpConfig {
     /// Creates a new configuration object for TCP/IP using the default Tor
     /// SOCKS5 port - 9050.
     fn default() -> Self {
-        Self {
-            sleep_on_error: Duration::from_millis(100),
-            ttl: None,
-            nodelay: None,
-            onion_map: HashMap::new(),
-            socks_port: DEFAULT_SOCKS_PORT,
-        }
+        Self::new(DEFAULT_SOCKS_PORT)
     }
 }
 
@@ -131,29 +614,52 @@ type Listener<TUpgrade, TError> =
 
 impl Transport for Socks5TokioTcpConfig {
     type Output = TokioTcpTransStream;
-    type Error = io::Error;
+    type Error = Socks5TransportError;
     type Listener = Listener<Self::ListenerUpgrade, Self::Error>;
     type ListenerUpgrade = Ready<Result<Self::Output, Self::Error>>;
-    type Dial = Pin<Box<dyn Future<Output = Result<TokioTcpTransStream, io::Error>> + Send>>;
+    type Dial = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
     fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
-        let port = match self.onion_map.get(&addr) {
-            Some(port) => port,
-            None => return Err(TransportError::MultiaddrNotSupported(addr)),
+        // New onion services forward to an ephemeral loopback port, mapped
+        // onion addresses are listened on at their local target, anything else
+        // must be an IP address.
+        let mut new_onion = None;
+        let (socket_addr, onion) = match self.onion_map.get(&addr).cloned() {
+            Some(LocalTarget::Socket(socket_addr)) => (socket_addr, Some(addr)),
+            #[cfg(unix)]
+            Some(LocalTarget::Unix(path)) => {
+                let listener = listen_unix(path, addr, self.sleep_on_error);
+                return Ok(Box::pin(listener.try_flatten_stream()));
+            }
+            None => match new_onion_service_port(&addr) {
+                Some(virtual_port) if self.control_port.is_some() => {
+                    new_onion = Some(virtual_port);
+                    (SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None)
+                }
+                Some(_) => return Err(TransportError::MultiaddrNotSupported(addr)),
+                None => match multiaddr_to_socketaddr(&addr) {
+                    Some(socket_addr) => (socket_addr, None),
+                    None => return Err(TransportError::MultiaddrNotSupported(addr)),
+                },
+            },
         };
-        let socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, *port));
 
         async fn do_listen(
             cfg: Socks5TokioTcpConfig,
             socket_addr: SocketAddr,
+            mut onion: Option<Multiaddr>,
+            new_onion: Option<u16>,
         ) -> Result<
             impl Stream<
                 Item = Result<
-                    ListenerEvent<Ready<Result<TokioTcpTransStream, io::Error>>, io::Error>,
-                    io::Error,
+                    ListenerEvent<
+                        Ready<Result<TokioTcpTransStream, Socks5TransportError>>,
+                        Socks5TransportError,
+                    >,
+                    Socks5TransportError,
                 >,
             >,
-            io::Error,
+            Socks5TransportError,
         > {
             let socket = if socket_addr.is_ipv4() {
                 Socket::new(
@@ -176,15 +682,15 @@ impl Transport for Socks5TokioTcpConfig {
             socket.bind(&socket_addr.into())?;
             socket.listen(1024)?; // we may want to make this configurable
 
-            let listener = <TcpListener>::try_from(socket.into_tcp_listener())
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let listener =
+                <TcpListener>::try_from(socket.into_tcp_listener()).map_err(io::Error::other)?;
 
             let local_addr = listener.local_addr()?;
             let port = local_addr.port();
 
             // Determine all our listen addresses which is either a single local IP address
             // or (if a wildcard IP address was used) the addresses of all our interfaces,
-            // as reported by `get_if_addrs`.
+            // as reported by `if_addrs`.
             let addrs = if socket_addr.ip().is_unspecified() {
                 let addrs = host_addresses(port)?;
                 debug!(
@@ -199,7 +705,7 @@ impl Transport for Socks5TokioTcpConfig {
             };
 
             // Generate `NewAddress` events for each new `Multiaddr`.
-            let pending = match addrs {
+            let mut pending = match addrs {
                 Addresses::One(ref ma) => {
                     let event = ListenerEvent::NewAddress(ma.clone());
                     let mut list = VecDeque::new();
@@ -215,12 +721,36 @@ impl Transport for Socks5TokioTcpConfig {
                     .collect::<VecDeque<_>>(),
             };
 
+            let mut control = None;
+            if let Some(virtual_port) = new_onion {
+                let mut conn = cfg.control().await?;
+                let created = conn
+                    .add_onion(virtual_port, local_addr)
+                    .await
+                    .map_err(Socks5TransportError::Control)?;
+                info!("Created onion service {}", created);
+                control = Some(conn);
+                onion = Some(created);
+            }
+
+            // Peers can only reach an onion listener through Tor, its loopback
+            // address stays internal unless asked for.
+            if let Some(ref onion) = onion {
+                debug!("Listening on {}", onion);
+                if !cfg.announce_loopback {
+                    pending.clear();
+                }
+                pending.push_front(Ok(ListenerEvent::NewAddress(onion.clone())));
+            }
+
             let listen_stream = TokioTcpListenStream {
                 stream: listener,
                 pause: None,
                 pause_duration: cfg.sleep_on_error,
                 port,
                 addrs,
+                onion,
+                _control: control,
                 pending,
                 config: cfg,
             };
@@ -228,43 +758,149 @@ impl Transport for Socks5TokioTcpConfig {
             Ok(stream::unfold(listen_stream, |s| s.next().map(Some)))
         }
 
-        Ok(Box::pin(do_listen(self, socket_addr).try_flatten_stream()))
+        Ok(Box::pin(
+            do_listen(self, socket_addr, onion, new_onion).try_flatten_stream(),
+        ))
     }
 
     fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
-        let dest =
-            tor_address_string(addr.clone()).ok_or(TransportError::MultiaddrNotSupported(addr))?;
-        debug!("Tor destination address: {}", dest);
+        // The address is handed back untouched if it is not supported.
+        let mut destination = addr.clone();
+        let peer_id = pop_peer_id(&mut destination);
+        let invalid_onion = match destination.iter().last() {
+            Some(Protocol::Onion3(onion)) => check_onion3(&onion).err(),
+            _ => None,
+        };
+        if let Some(reason) = invalid_onion {
+            let err = Socks5TransportError::InvalidOnionAddress(destination, reason);
+            return Err(TransportError::Other(err));
+        }
+        let dest = match socks_target(&destination) {
+            Some(dest) if self.can_reach(&dest) => dest,
+            _ => return Err(TransportError::MultiaddrNotSupported(addr)),
+        };
+        // Only for supported addresses, others are left to other transports.
+        self.check_filter(&addr).map_err(TransportError::Other)?;
+        debug!("{:?} destination address: {}", self.final_protocol(), dest);
+        let route = self.route(&dest).map_err(TransportError::Other)?;
+
+        let isolation = self
+            .isolation
+            .as_ref()
+            .map(|isolation| isolation.credentials(&dest, peer_id.as_ref()));
+        let info = DialInfo {
+            destination,
+            proxy: None,
+            isolation_key: isolation.as_ref().map(|creds| creds.password.clone()),
+            proxy_connected_at: None,
+            handshake_completed_at: None,
+        };
 
         async fn do_dial(
             cfg: Socks5TokioTcpConfig,
-            dest: String,
-        ) -> Result<TokioTcpTransStream, io::Error> {
-            info!("Connecting to Tor proxy ...");
-            let stream = connect_to_socks_proxy(dest, cfg.socks_port)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
-            info!("Connection established");
+            dest: TargetAddr,
+            isolation: Option<Credentials>,
+            mut info: DialInfo,
+        ) -> Result<TokioTcpTransStream, Socks5TransportError> {
+            let (stream, proxy, connected_at) =
+                connect_to_proxy(&dest, &cfg, isolation.as_ref()).await?;
+            info!("Connection to {} established", info.destination);
+            info.proxy = Some(proxy);
+            info.proxy_connected_at = Some(connected_at);
+            info.handshake_completed_at = Some(SystemTime::now());
+
+            if let Connection::Tcp(ref stream) = stream {
+                apply_config(&cfg, stream).map_err(Socks5TransportError::SocketConfig)?;
+            }
 
-            apply_config(&cfg, &stream)?;
+            Ok(TokioTcpTransStream {
+                inner: stream,
+                info: Some(info),
+            })
+        }
+
+        async fn do_dial_direct(
+            cfg: Socks5TokioTcpConfig,
+            dest: TargetAddr,
+            info: DialInfo,
+        ) -> Result<TokioTcpTransStream, Socks5TransportError> {
+            let stream = match dest {
+                TargetAddr::Ip(addr) => TcpStream::connect(addr).await?,
+                TargetAddr::Domain(name, port) => TcpStream::connect((name.as_str(), port)).await?,
+            };
+            apply_config(&cfg, &stream).map_err(Socks5TransportError::SocketConfig)?;
 
-            Ok(TokioTcpTransStream { inner: stream })
+            Ok(TokioTcpTransStream {
+                inner: Connection::Tcp(stream),
+                info: Some(info),
+            })
         }
 
-        Ok(Box::pin(do_dial(self, dest)))
+        match route {
+            Route::Direct => Ok(Box::pin(do_dial_direct(self, dest, info))),
+            _ => Ok(Box::pin(do_dial(self, dest, isolation, info))),
+        }
     }
 
     /// Performs a transport-specific mapping of an address `observed` by
     /// a remote onto a local `listen` address to yield an address for
     /// the local node that may be reachable for other peers.
-    fn address_translation(&self, _listen: &Multiaddr, _observed: &Multiaddr) -> Option<Multiaddr> {
-        // Is this ever useful for Onion addresses?
-        None
+    ///
+    /// Tor connects to our listeners from loopback, so remotes observe a
+    /// loopback address. If that is on a port of the onion map, the onion
+    /// address is what others can reach. When several onion addresses map to
+    /// the port, `listen` is picked if it is one of them, otherwise nothing.
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        let observed_port = loopback_tcp_port(observed)?;
+        let maps_to_port = |target: &LocalTarget| target.port() == Some(observed_port);
+        if matches!(self.onion_map.get(listen), Some(target) if maps_to_port(target)) {
+            return Some(listen.clone());
+        }
+        let mut onions = self
+            .onion_map
+            .iter()
+            .filter(|(_, target)| maps_to_port(target))
+            .map(|(onion, _)| onion);
+        match (onions.next(), onions.next()) {
+            (Some(onion), None) => Some(onion.clone()),
+            _ => None,
+        }
     }
 }
 
+// Returns the port of a `/ip4/127.0.0.1/tcp/PORT` style loopback address.
+fn loopback_tcp_port(multi: &Multiaddr) -> Option<u16> {
+    let mut iter = multi.iter();
+    let is_loopback = match iter.next()? {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    };
+    match (is_loopback, iter.next()?, iter.next()) {
+        (true, Protocol::Tcp(port), None) => Some(port),
+        _ => None,
+    }
+}
+
+// Removes a trailing `/p2p/<peer id>` from the multiaddr, returning the peer id.
+fn pop_peer_id(multi: &mut Multiaddr) -> Option<PeerId> {
+    let hash = match multi.iter().last()? {
+        Protocol::P2p(hash) => hash,
+        _ => return None,
+    };
+    multi.pop();
+    PeerId::from_multihash(hash).ok()
+}
+
+// Maps a multiaddr onto the target address sent to the SOCKS5 proxy.
+fn socks_target(multi: &Multiaddr) -> Option<TargetAddr> {
+    tor_target(multi.clone())
+        .or_else(|| dns_target(multi.clone()))
+        .or_else(|| ip_target(multi.clone()))
+}
+
 // Tor expects address in form: ADDR.onion:PORT
-fn tor_address_string(mut multi: Multiaddr) -> Option<String> {
+fn tor_target(mut multi: Multiaddr) -> Option<TargetAddr> {
     let (encoded, port) = match multi.pop()? {
         Protocol::Onion(addr, port) => {
             log::warn!("Onion service v2 is being deprecated, consider upgrading to v3");
@@ -273,18 +909,215 @@ fn tor_address_string(mut multi: Multiaddr) -> Option<String> {
         Protocol::Onion3(addr) => (BASE32.encode(addr.hash()), addr.port()),
         _ => return None,
     };
-    let addr = format!("{}.onion:{}", encoded.to_lowercase(), port);
-    Some(addr)
+    let name = format!("{}.onion", encoded.to_lowercase());
+    Some(TargetAddr::Domain(name, port))
 }
 
-/// Connect to the SOCKS5 proxy socket.
-async fn connect_to_socks_proxy<'a>(
-    dest: impl IntoTargetAddr<'a>,
-    port: u16,
-) -> Result<TcpStream, tokio_socks::Error> {
-    let sock = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
-    let stream = Socks5Stream::connect(sock, dest).await?;
-    Ok(stream.into_inner())
+// Checks the version byte and the checksum of an onion v3 address, catches
+// typos before Tor spends a descriptor fetch on them. See rend-spec-v3.txt.
+fn check_onion3(onion: &Onion3Addr) -> Result<(), &'static str> {
+    let (pubkey, rest) = onion.hash().split_at(32);
+    let (checksum, version) = rest.split_at(2);
+    if version != [ONION3_VERSION] {
+        return Err("unsupported version");
+    }
+
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update(version);
+    if hasher.finalize()[..2] != *checksum {
+        return Err("checksum mismatch");
+    }
+    Ok(())
+}
+
+// Domain names are sent as a domain name target so that name resolution
+// happens on the proxy side.
+fn dns_target(mut multi: Multiaddr) -> Option<TargetAddr> {
+    let port = match multi.pop()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    let name = match multi.pop()? {
+        Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => name,
+        _ => return None,
+    };
+    if multi.pop().is_some() {
+        return None;
+    }
+    Some(TargetAddr::Domain(name.into_owned(), port))
+}
+
+// IP addresses are sent as an IPv4 or IPv6 target.
+fn ip_target(mut multi: Multiaddr) -> Option<TargetAddr> {
+    let port = match multi.pop()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    let ip = match multi.pop()? {
+        Protocol::Ip4(ip) => IpAddr::V4(ip),
+        Protocol::Ip6(ip) => IpAddr::V6(ip),
+        _ => return None,
+    };
+    if multi.pop().is_some() {
+        return None;
+    }
+    Some(TargetAddr::Ip(SocketAddr::new(ip, port)))
+}
+
+/// Connect to the proxy socket and tunnel through the proxy chain to `dest`.
+/// Every proxy is authenticated with its own credentials, except that the last
+/// one, which connects to `dest`, gets the stream `isolation` credentials if
+/// given. Returns the proxy endpoint used and when we connected to it along
+/// with the stream.
+async fn connect_to_proxy(
+    dest: &TargetAddr,
+    config: &Socks5TokioTcpConfig,
+    isolation: Option<&Credentials>,
+) -> Result<(Connection, ProxyAddr, SystemTime), Socks5TransportError> {
+    let (mut stream, proxy, _dial) = connect_to_endpoint(config).await?;
+    let connected_at = SystemTime::now();
+
+    let mut protocol = config.protocol;
+    let mut credentials = config.credentials.as_ref();
+    for hop in &config.chain {
+        debug!("Tunneling to {:?} proxy at {}", hop.protocol, hop.addr);
+        handshake(&mut stream, config, protocol, &hop.addr, credentials).await?;
+        protocol = hop.protocol;
+        credentials = hop.credentials.as_ref();
+    }
+    let isolation = isolation.map(|creds| creds.isolating(protocol));
+    let credentials = isolation.as_ref().or(credentials);
+    handshake(&mut stream, config, protocol, dest, credentials).await?;
+
+    Ok((stream, proxy, connected_at))
+}
+
+/// Connect to one of the proxy endpoints, trying them in the order picked by
+/// the pool. Endpoints that fail to connect are marked unhealthy.
+async fn connect_to_endpoint(
+    config: &Socks5TokioTcpConfig,
+) -> Result<(Connection, ProxyAddr, Outstanding), Socks5TransportError> {
+    let mut last_err = None;
+    for index in config.proxies.candidates() {
+        let addr = config.proxies.endpoint(index);
+        info!("Connecting to {:?} proxy at {} ...", config.protocol, addr);
+
+        let dial = config.proxies.start_dial(index);
+        let connect = addr.connect().map_err(Socks5TransportError::ProxyConnect);
+        match timeout(
+            config.proxy_connect_timeout,
+            connect,
+            Socks5TransportError::ProxyConnectTimeout,
+        )
+        .await
+        {
+            Ok(stream) => {
+                config.proxies.mark_healthy(index);
+                return Ok((stream, addr.clone(), dial));
+            }
+            Err(e) => {
+                warn!("Marking proxy at {} unhealthy: {}", addr, e);
+                config.proxies.mark_unhealthy(index);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_proxy_endpoints))
+}
+
+fn no_proxy_endpoints() -> Socks5TransportError {
+    let e = io::Error::new(io::ErrorKind::NotFound, "no proxy endpoints configured");
+    Socks5TransportError::ProxyConnect(e)
+}
+
+/// Runs the handshake for `protocol` on `stream` asking the proxy to connect to
+/// `dest`. Errors record the phase of the handshake that failed.
+async fn handshake<S>(
+    stream: &mut S,
+    config: &Socks5TokioTcpConfig,
+    protocol: ProxyProtocol,
+    dest: &TargetAddr,
+    credentials: Option<&Credentials>,
+) -> Result<(), Socks5TransportError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use Socks5TransportError::*;
+
+    match protocol {
+        ProxyProtocol::Socks5 => {
+            negotiate(stream, config, credentials).await?;
+            let connect = socks::connect(stream, dest).map_err(|e| ConnectReply(e.into()));
+            timeout(config.connect_timeout, connect, ConnectReplyTimeout).await?;
+        }
+        ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+            let resolve_remotely = protocol == ProxyProtocol::Socks4a;
+            let connect = socks::connect_v4(stream, dest, credentials, resolve_remotely)
+                .map_err(|e| ConnectReply(e.into()));
+            timeout(config.connect_timeout, connect, ConnectReplyTimeout).await?;
+        }
+        ProxyProtocol::HttpConnect => {
+            let connect = http::connect(stream, dest, credentials).map_err(|e| match e.kind() {
+                io::ErrorKind::PermissionDenied => Authentication(e),
+                _ => ConnectReply(e),
+            });
+            timeout(config.connect_timeout, connect, ConnectReplyTimeout).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Negotiates the auth method with the SOCKS5 proxy on `stream` and, if
+/// selected, authenticates with `credentials`.
+async fn negotiate<S>(
+    stream: &mut S,
+    config: &Socks5TokioTcpConfig,
+    credentials: Option<&Credentials>,
+) -> Result<(), Socks5TransportError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let negotiate = async {
+        let authenticate = socks::negotiate(stream, credentials)
+            .await
+            .map_err(|e| Socks5TransportError::MethodNegotiation(e.into()))?;
+        if let (true, Some(creds)) = (authenticate, credentials) {
+            socks::authenticate(stream, creds)
+                .await
+                .map_err(|e| Socks5TransportError::Authentication(e.into()))?;
+        }
+        Ok(())
+    };
+    timeout(
+        config.negotiation_timeout,
+        negotiate,
+        Socks5TransportError::NegotiationTimeout,
+    )
+    .await
+}
+
+/// Runs `fut` to completion or fails with `on_timeout` once `duration` has
+/// elapsed, `None` waits forever.
+async fn timeout<F, T>(
+    duration: Option<Duration>,
+    fut: F,
+    on_timeout: fn(Duration) -> Socks5TransportError,
+) -> Result<T, Socks5TransportError>
+where
+    F: Future<Output = Result<T, Socks5TransportError>>,
+{
+    let duration = match duration {
+        Some(duration) => duration,
+        None => return fut.await,
+    };
+    futures::pin_mut!(fut);
+    match future::select(fut, Delay::new(duration)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(on_timeout(duration)),
+    }
 }
 
 /// Stream that listens on an TCP/IP address.
@@ -300,6 +1133,11 @@ pub struct TokioTcpListenStream {
     port: u16,
     /// The set of known addresses.
     addrs: Addresses,
+    /// The onion address, for listeners on an onion map entry or a new onion
+    /// service.
+    onion: Option<Multiaddr>,
+    /// Control connection keeping a new onion service alive.
+    _control: Option<TorControl>,
     /// Temporary buffer of listener events.
     pending: Buffer<TokioTcpTransStream>,
     /// Original configuration.
@@ -312,7 +1150,13 @@ impl TokioTcpListenStream {
     async fn next(
         mut self,
     ) -> (
-        Result<ListenerEvent<Ready<Result<TokioTcpTransStream, io::Error>>, io::Error>, io::Error>,
+        Result<
+            ListenerEvent<
+                Ready<Result<TokioTcpTransStream, Socks5TransportError>>,
+                Socks5TransportError,
+            >,
+            Socks5TransportError,
+        >,
         Self,
     ) {
         loop {
@@ -330,7 +1174,7 @@ impl TokioTcpListenStream {
                 Err(e) => {
                     debug!("error accepting incoming connection: {}", e);
                     self.pause = Some(Delay::new(self.pause_duration));
-                    return (Ok(ListenerEvent::Error(e)), self);
+                    return (Ok(ListenerEvent::Error(e.into())), self);
                 }
             };
 
@@ -344,17 +1188,24 @@ impl TokioTcpListenStream {
 
             let local_addr = match sock.local_addr() {
                 Ok(sock_addr) => {
+                    // Interface addresses of an onion listener stay internal
+                    // like its bind address.
+                    let announce = self.onion.is_none() || self.config.announce_loopback;
                     if let Addresses::Many(ref mut addrs) = self.addrs {
                         if let Err(err) = check_for_interface_changes(
                             &sock_addr,
                             self.port,
                             addrs,
                             &mut self.pending,
+                            announce,
                         ) {
-                            return (Ok(ListenerEvent::Error(err)), self);
+                            return (Ok(ListenerEvent::Error(err.into())), self);
                         }
                     }
-                    ip_to_multiaddr(sock_addr.ip(), sock_addr.port())
+                    match self.onion {
+                        Some(ref onion) => onion.clone(),
+                        None => ip_to_multiaddr(sock_addr.ip(), sock_addr.port()),
+                    }
                 }
                 Err(err) => {
                     debug!("Failed to get local address of incoming socket: {:?}", err);
@@ -368,7 +1219,10 @@ impl TokioTcpListenStream {
                 Ok(()) => {
                     trace!("Incoming connection from {} at {}", remote_addr, local_addr);
                     self.pending.push_back(Ok(ListenerEvent::Upgrade {
-                        upgrade: future::ok(TokioTcpTransStream { inner: sock }),
+                        upgrade: future::ok(TokioTcpTransStream {
+                            inner: Connection::Tcp(sock),
+                            info: None,
+                        }),
                         local_addr,
                         remote_addr,
                     }))
@@ -379,7 +1233,7 @@ impl TokioTcpListenStream {
                         remote_addr, err
                     );
                     self.pending.push_back(Ok(ListenerEvent::Upgrade {
-                        upgrade: future::err(err),
+                        upgrade: future::err(Socks5TransportError::SocketConfig(err)),
                         local_addr,
                         remote_addr,
                     }))
@@ -389,19 +1243,202 @@ impl TokioTcpListenStream {
     }
 }
 
-/// Wraps around a `TcpStream` and adds logging for important events.
+/// Listens on the Unix domain socket at `path` for connections Tor forwards to
+/// `onion`.
+#[cfg(unix)]
+async fn listen_unix(
+    path: PathBuf,
+    onion: Multiaddr,
+    sleep_on_error: Duration,
+) -> Result<
+    impl Stream<
+        Item = Result<
+            ListenerEvent<
+                Ready<Result<TokioTcpTransStream, Socks5TransportError>>,
+                Socks5TransportError,
+            >,
+            Socks5TransportError,
+        >,
+    >,
+    Socks5TransportError,
+> {
+    // A socket left behind by a previous run would make binding fail.
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            debug!("Removing stale socket unix:{}", path.display());
+            std::fs::remove_file(&path)?;
+        }
+    }
+    let listener = UnixSocketListener {
+        listener: UnixListener::bind(&path)?,
+        path: path.clone(),
+    };
+    debug!("Listening on {} at unix:{}", onion, path.display());
+
+    let remote_addr: Multiaddr = Protocol::Unix(path.to_string_lossy()).into();
+    let new_address = ListenerEvent::NewAddress(onion.clone());
+    let incoming = stream::unfold(listener, move |mut listener| {
+        let local_addr = onion.clone();
+        let remote_addr = remote_addr.clone();
+        async move {
+            let event = match listener.listener.accept().await {
+                Ok((sock, _)) => ListenerEvent::Upgrade {
+                    upgrade: future::ok(TokioTcpTransStream {
+                        inner: Connection::Unix(sock),
+                        info: None,
+                    }),
+                    local_addr,
+                    remote_addr,
+                },
+                Err(e) => {
+                    debug!("error accepting incoming connection: {}", e);
+                    Delay::new(sleep_on_error).await;
+                    ListenerEvent::Error(e.into())
+                }
+            };
+            Some((Ok(event), listener))
+        }
+    });
+
+    Ok(stream::once(future::ok(new_address)).chain(incoming))
+}
+
+/// A Unix domain socket listener removing its socket file when dropped.
+#[cfg(unix)]
+struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("Failed to remove unix:{}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Wraps around a `TcpStream`, or a `UnixStream` to the proxy, and adds logging
+/// for important events.
 #[cfg_attr(docsrs, doc(cfg(feature = $feature_name)))]
 #[derive(Debug)]
 pub struct TokioTcpTransStream {
-    inner: TcpStream,
+    inner: Connection,
+    /// What we know about the dial, `None` for incoming connections.
+    info: Option<DialInfo>,
+}
+
+impl TokioTcpTransStream {
+    /// The dialed address without `/p2p/<peer id>` e.g., the onion address.
+    /// Returns `None` for incoming connections.
+    pub fn destination(&self) -> Option<&Multiaddr> {
+        self.info.as_ref().map(|info| &info.destination)
+    }
+
+    /// The proxy endpoint the connection goes through, `None` for direct dials
+    /// and incoming connections.
+    pub fn proxy(&self) -> Option<&ProxyAddr> {
+        self.info.as_ref()?.proxy.as_ref()
+    }
+
+    /// The Tor stream isolation key of the connection, if stream isolation is
+    /// configured.
+    pub fn isolation_key(&self) -> Option<&str> {
+        self.info.as_ref()?.isolation_key.as_deref()
+    }
+
+    /// When the connection to the proxy was opened.
+    pub fn proxy_connected_at(&self) -> Option<SystemTime> {
+        self.info.as_ref()?.proxy_connected_at
+    }
+
+    /// When the proxy handshake completed, from then on the connection is
+    /// tunneled through to the destination.
+    pub fn handshake_completed_at(&self) -> Option<SystemTime> {
+        self.info.as_ref()?.handshake_completed_at
+    }
+}
+
+/// What we know about a dialed connection.
+#[derive(Debug)]
+struct DialInfo {
+    destination: Multiaddr,
+    proxy: Option<ProxyAddr>,
+    isolation_key: Option<String>,
+    proxy_connected_at: Option<SystemTime>,
+    handshake_completed_at: Option<SystemTime>,
 }
 
 impl Drop for TokioTcpTransStream {
     fn drop(&mut self) {
-        if let Ok(addr) = self.inner.peer_addr() {
-            debug!("Dropped TCP connection to {:?}", addr);
-        } else {
-            debug!("Dropped TCP connection to undeterminate peer");
+        // The peer address of a dialed connection is the proxy.
+        if let Some(ref info) = self.info {
+            debug!("Dropped connection to {}", info.destination);
+            return;
+        }
+        match self.inner {
+            Connection::Tcp(ref stream) => {
+                if let Ok(addr) = stream.peer_addr() {
+                    debug!("Dropped TCP connection to {:?}", addr);
+                } else {
+                    debug!("Dropped TCP connection to undeterminate peer");
+                }
+            }
+            #[cfg(unix)]
+            Connection::Unix(_) => debug!("Dropped connection over Unix domain socket"),
+        }
+    }
+}
+
+/// A connection to the proxy or, for listeners, to the remote.
+#[derive(Debug)]
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl tokio::io::AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => tokio::io::AsyncRead::poll_read(Pin::new(s), cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => tokio::io::AsyncRead::poll_read(Pin::new(s), cx, buf),
+        }
+    }
+}
+
+impl tokio::io::AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => tokio::io::AsyncWrite::poll_write(Pin::new(s), cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => tokio::io::AsyncWrite::poll_write(Pin::new(s), cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => tokio::io::AsyncWrite::poll_flush(Pin::new(s), cx),
+            #[cfg(unix)]
+            Connection::Unix(s) => tokio::io::AsyncWrite::poll_flush(Pin::new(s), cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => tokio::io::AsyncWrite::poll_shutdown(Pin::new(s), cx),
+            #[cfg(unix)]
+            Connection::Unix(s) => tokio::io::AsyncWrite::poll_shutdown(Pin::new(s), cx),
         }
     }
 }
@@ -447,6 +1484,20 @@ impl AsyncWrite for TokioTcpTransStream {
     }
 }
 
+// Parses a `/ip4/IP/tcp/PORT` or `/ip6/IP/tcp/PORT` listen address.
+fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Option<SocketAddr> {
+    let mut iter = addr.iter();
+    let ip = match iter.next()? {
+        Protocol::Ip4(ip) => IpAddr::V4(ip),
+        Protocol::Ip6(ip) => IpAddr::V6(ip),
+        _ => return None,
+    };
+    match (iter.next()?, iter.next()) {
+        (Protocol::Tcp(port), None) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    }
+}
+
 // Create a [`Multiaddr`] from the given IP address and port number.
 fn ip_to_multiaddr(ip: IpAddr, port: u16) -> Multiaddr {
     let proto = match ip {
@@ -493,7 +1544,12 @@ enum Addresses {
     Many(Vec<(IpAddr, IpNet, Multiaddr)>),
 }
 
-type Buffer<T> = VecDeque<Result<ListenerEvent<Ready<Result<T, io::Error>>, io::Error>, io::Error>>;
+type Buffer<T> = VecDeque<
+    Result<
+        ListenerEvent<Ready<Result<T, Socks5TransportError>>, Socks5TransportError>,
+        Socks5TransportError,
+    >,
+>;
 
 // If we listen on all interfaces, find out to which interface the given
 // socket address belongs. In case we think the address is new, check
@@ -503,6 +1559,7 @@ fn check_for_interface_changes<T>(
     listen_port: u16,
     listen_addrs: &mut Vec<(IpAddr, IpNet, Multiaddr)>,
     pending: &mut Buffer<T>,
+    announce: bool,
 ) -> Result<(), io::Error> {
     // Check for exact match:
     if listen_addrs.iter().any(|(ip, ..)| ip == &socket_addr.ip()) {
@@ -519,14 +1576,14 @@ fn check_for_interface_changes<T>(
 
     // The local IP address of this socket is new to us.
     // We check for changes in the set of host addresses and report new
-    // and expired addresses.
+    // and expired addresses, unless they are not to be announced.
     //
     // TODO: We do not detect expired addresses unless there is a new address.
     let old_listen_addrs = std::mem::replace(listen_addrs, host_addresses(listen_port)?);
 
     // Check for addresses no longer in use.
     for (ip, _, ma) in old_listen_addrs.iter() {
-        if listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
+        if announce && listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
             debug!("Expired listen address: {}", ma);
             pending.push_back(Ok(ListenerEvent::AddressExpired(ma.clone())));
         }
@@ -534,7 +1591,7 @@ fn check_for_interface_changes<T>(
 
     // Check for new addresses.
     for (ip, _, ma) in listen_addrs.iter() {
-        if old_listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
+        if announce && old_listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
             debug!("New listen address: {}", ma);
             pending.push_back(Ok(ListenerEvent::NewAddress(ma.clone())));
         }
@@ -548,7 +1605,7 @@ fn check_for_interface_changes<T>(
         .is_none()
     {
         let msg = format!("{} does not match any listen address", socket_addr.ip());
-        return Err(io::Error::new(io::ErrorKind::Other, msg));
+        return Err(io::Error::other(msg));
     }
 
     Ok(())
@@ -556,17 +1613,44 @@ fn check_for_interface_changes<T>(
 
 #[cfg(test)]
 mod tests {
-    use super::tor_address_string;
+    use super::{
+        check_for_interface_changes, check_onion3, connect_to_proxy, dns_target, ip_target,
+        pop_peer_id, socks::tests::mock_proxy, tor_target, Buffer, Credentials, Destination,
+        DestinationPolicy, ProxyAddr, ProxyHop, ProxyProtocol, Route, RoutingPolicy,
+        SelectionStrategy, Socks5TokioTcpConfig, Socks5TransportError, StreamIsolation, TargetAddr,
+        TokioTcpTransStream,
+    };
+    use libp2p::core::{
+        multiaddr::Protocol,
+        transport::{Transport, TransportError},
+        Multiaddr, PeerId,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn can_check_onion3_address() {
+        let onion = |s: &str| match s.parse::<Multiaddr>().unwrap().pop() {
+            Some(Protocol::Onion3(onion)) => onion.acquire(),
+            _ => unreachable!(),
+        };
+
+        let valid = onion("/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234");
+        assert_eq!(check_onion3(&valid), Ok(()));
+
+        // First character mistyped.
+        let typo = onion("/onion3/vsw6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234");
+        assert_eq!(check_onion3(&typo), Err("checksum mismatch"));
+    }
 
     #[test]
     fn can_format_tor_address_v3() {
         let multi = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234"
             .parse()
             .expect("failed to parse multiaddr");
-        let want = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion:1234";
-        let got = tor_address_string(multi).expect("failed to stringify");
+        let want = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion";
+        let got = tor_target(multi).expect("failed to map");
 
-        assert_eq!(got, want);
+        assert_eq!(got, TargetAddr::Domain(want.to_string(), 1234));
     }
 
     #[test]
@@ -574,9 +1658,650 @@ mod tests {
         let multi = "/onion/aaimaq4ygg2iegci:80"
             .parse()
             .expect("failed to parse multiaddr");
-        let want = "aaimaq4ygg2iegci.onion:80";
-        let got = tor_address_string(multi).expect("failed to stringify");
+        let want = "aaimaq4ygg2iegci.onion";
+        let got = tor_target(multi).expect("failed to map");
+
+        assert_eq!(got, TargetAddr::Domain(want.to_string(), 80));
+    }
+
+    #[test]
+    fn can_format_dns_address() {
+        for multi in &[
+            "/dns/example.com/tcp/443",
+            "/dns4/example.com/tcp/443",
+            "/dns6/example.com/tcp/443",
+        ] {
+            let multi = multi.parse().expect("failed to parse multiaddr");
+            let got = dns_target(multi).expect("failed to map");
+
+            assert_eq!(got, TargetAddr::Domain("example.com".to_string(), 443));
+        }
+    }
+
+    #[test]
+    fn cannot_format_non_tcp_dns_address() {
+        let multi = "/dns4/example.com/udp/443"
+            .parse()
+            .expect("failed to parse multiaddr");
+
+        assert!(dns_target(multi).is_none());
+    }
+
+    #[test]
+    fn can_format_ip_address() {
+        let multi = "/ip4/203.0.113.7/tcp/80"
+            .parse()
+            .expect("failed to parse multiaddr");
+        let got = ip_target(multi).expect("failed to map");
+
+        assert_eq!(got, TargetAddr::Ip("203.0.113.7:80".parse().unwrap()));
+
+        let multi = "/ip6/2001:db8::1/tcp/80"
+            .parse()
+            .expect("failed to parse multiaddr");
+        let got = ip_target(multi).expect("failed to map");
+
+        assert_eq!(got, TargetAddr::Ip("[2001:db8::1]:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn credentials_are_redacted_in_debug_output() {
+        let creds = Credentials::new("alice", "hunter2");
+        let debug = format!("{:?}", creds);
+
+        assert!(!debug.contains("alice"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn control_password_is_redacted_in_debug_output() {
+        let config = Socks5TokioTcpConfig::default().control_password("hunter2");
+        let debug = format!("{:?}", config);
+
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn per_peer_isolation_uses_peer_id_from_multiaddr() {
+        let peer_id = PeerId::random();
+        let mut multi: Multiaddr = format!("/dns4/example.com/tcp/443/p2p/{}", peer_id)
+            .parse()
+            .expect("failed to parse multiaddr");
+
+        assert_eq!(pop_peer_id(&mut multi), Some(peer_id));
+        assert_eq!(multi, "/dns4/example.com/tcp/443".parse().unwrap());
+
+        let onion_a = TargetAddr::Domain("a.onion".to_string(), 1);
+        let onion_b = TargetAddr::Domain("b.onion".to_string(), 1);
+        let isolation = StreamIsolation::PerPeer;
+        let one = isolation.credentials(&onion_a, Some(&peer_id));
+        let two = isolation.credentials(&onion_b, Some(&peer_id));
+        let other = isolation.credentials(&onion_a, Some(&PeerId::random()));
+
+        assert_eq!(one, two);
+        assert_ne!(one, other);
+    }
+
+    #[test]
+    fn unsupported_address_is_returned_with_peer_id() {
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/udp/1234/p2p/{}", PeerId::random())
+            .parse()
+            .unwrap();
+
+        match Socks5TokioTcpConfig::default().dial(addr.clone()) {
+            Err(TransportError::MultiaddrNotSupported(a)) => assert_eq!(a, addr),
+            _ => panic!("expected MultiaddrNotSupported"),
+        }
+    }
+
+    #[test]
+    fn routing_policy_rejects_before_dialing() {
+        let config = Socks5TokioTcpConfig::default()
+            .routing_policy(RoutingPolicy::default().clearnet(Route::Reject));
+
+        match config.dial("/dns/example.com/tcp/443".parse().unwrap()) {
+            Err(TransportError::Other(Socks5TransportError::RoutingRejected(d))) => {
+                assert_eq!(d, Destination::Clearnet)
+            }
+            _ => panic!("dial not rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_route_bypasses_the_proxy() {
+        use tokio::net::TcpListener;
+
+        // Nothing listens on the proxy port, dialing through it would fail.
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        drop(proxy);
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(proxy_addr)
+            .routing_policy(RoutingPolicy::default().loopback(Route::Direct));
+        let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+        let (dialed, accepted) = futures::join!(config.dial(addr).unwrap(), listener.accept());
+
+        assert_eq!(dialed.unwrap().proxy(), None);
+        accepted.unwrap();
+    }
+
+    #[test]
+    fn destination_filter_rejects_before_dialing() {
+        let config = Socks5TokioTcpConfig::default()
+            .destination_filter(DestinationPolicy::default().onion3_only());
+        let addr: Multiaddr = "/dns/example.com/tcp/443".parse().unwrap();
+
+        match config.dial(addr.clone()) {
+            Err(TransportError::Other(Socks5TransportError::DestinationRejected(a, _))) => {
+                assert_eq!(a, addr)
+            }
+            _ => panic!("dial not rejected"),
+        }
+    }
+
+    #[test]
+    fn destination_filter_leaves_unsupported_addresses_to_other_transports() {
+        let config = Socks5TokioTcpConfig::default()
+            .destination_filter(DestinationPolicy::default().onion3_only());
+
+        for addr in &["/memory/1", "/ip4/203.0.113.7/udp/443"] {
+            let addr: Multiaddr = addr.parse().unwrap();
+            match config.clone().dial(addr.clone()) {
+                Err(TransportError::MultiaddrNotSupported(a)) => assert_eq!(a, addr),
+                _ => panic!("expected MultiaddrNotSupported for {}", addr),
+            }
+        }
+    }
+
+    #[test]
+    fn translates_observed_loopback_to_onion_address() {
+        let onion: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234"
+                .parse()
+                .unwrap();
+        let mut map = HashMap::new();
+        map.insert(onion.clone(), 7777);
+        let config = Socks5TokioTcpConfig::default().onion_map(map);
+        let listen = "/ip4/127.0.0.1/tcp/7777".parse().unwrap();
+        let translate =
+            |observed: &str| config.address_translation(&listen, &observed.parse().unwrap());
+
+        assert_eq!(translate("/ip4/127.0.0.1/tcp/7777"), Some(onion.clone()));
+        assert_eq!(translate("/ip4/127.0.0.1/tcp/41234"), None);
+        assert_eq!(translate("/ip4/203.0.113.7/tcp/7777"), None);
+
+        let other = "/ip4/127.0.0.1/tcp/8888".parse().unwrap();
+        let observed = "/ip4/127.0.0.1/tcp/41234".parse().unwrap();
+        assert_eq!(config.address_translation(&other, &observed), None);
+        let observed = "/ip4/127.0.0.1/tcp/7777".parse().unwrap();
+        assert_eq!(config.address_translation(&other, &observed), Some(onion));
+    }
+
+    #[test]
+    fn onion_targets_are_added_to_the_onion_map() {
+        let onion_a: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234"
+                .parse()
+                .unwrap();
+        let onion_b: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:5678"
+                .parse()
+                .unwrap();
+        let target = super::LocalTarget::Socket("[::1]:7777".parse().unwrap());
+        let mut ports = HashMap::new();
+        ports.insert(onion_a.clone(), 7777);
+        let mut targets = HashMap::new();
+        targets.insert(onion_b.clone(), target.clone());
+
+        let config = Socks5TokioTcpConfig::default()
+            .onion_map(ports)
+            .onion_targets(targets);
+
+        assert_eq!(config.onion_map[&onion_a], super::LocalTarget::from(7777));
+        assert_eq!(config.onion_map[&onion_b], target);
+    }
+
+    #[test]
+    fn translates_to_listened_onion_address_if_several_share_the_port() {
+        let onion_a: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234"
+                .parse()
+                .unwrap();
+        let onion_b: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:5678"
+                .parse()
+                .unwrap();
+        let mut map = HashMap::new();
+        map.insert(onion_a.clone(), 7777);
+        map.insert(onion_b.clone(), 7777);
+        let config = Socks5TokioTcpConfig::default().onion_map(map);
+        let observed = "/ip4/127.0.0.1/tcp/7777".parse().unwrap();
+
+        assert_eq!(
+            config.address_translation(&onion_a, &observed),
+            Some(onion_a)
+        );
+        assert_eq!(
+            config.address_translation(&onion_b, &observed),
+            Some(onion_b)
+        );
+        let listen = "/ip4/127.0.0.1/tcp/7777".parse().unwrap();
+        assert_eq!(config.address_translation(&listen, &observed), None);
+    }
+
+    #[test]
+    fn per_dial_isolation_never_reuses_credentials() {
+        let onion_a = TargetAddr::Domain("a.onion".to_string(), 1);
+        let isolation = StreamIsolation::PerDial;
+        let one = isolation.credentials(&onion_a, None);
+        let two = isolation.credentials(&onion_a, None);
+
+        assert_ne!(one, two);
+    }
+
+    #[test]
+    fn socks4_rejects_unreachable_targets_before_dialing() {
+        let not_supported = |protocol, addr: &str| {
+            let config = Socks5TokioTcpConfig::default().protocol(protocol);
+            matches!(
+                config.dial(addr.parse().unwrap()),
+                Err(TransportError::MultiaddrNotSupported(_))
+            )
+        };
+        let onion = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234";
+
+        assert!(not_supported(
+            ProxyProtocol::Socks4,
+            "/dns/example.com/tcp/443"
+        ));
+        assert!(not_supported(ProxyProtocol::Socks4, onion));
+        assert!(not_supported(
+            ProxyProtocol::Socks4,
+            "/ip6/2001:db8::1/tcp/443"
+        ));
+        assert!(!not_supported(
+            ProxyProtocol::Socks4,
+            "/ip4/203.0.113.7/tcp/443"
+        ));
+        assert!(not_supported(
+            ProxyProtocol::Socks4a,
+            "/ip6/2001:db8::1/tcp/443"
+        ));
+        assert!(!not_supported(
+            ProxyProtocol::Socks4a,
+            "/dns/example.com/tcp/443"
+        ));
+        assert!(!not_supported(ProxyProtocol::Socks4a, onion));
+    }
+
+    #[test]
+    fn socks4_isolation_keeps_key_in_user_id() {
+        let creds = Credentials::new("key", "alice");
+
+        assert_eq!(
+            creds.isolating(ProxyProtocol::Socks4a),
+            Credentials::new("key:alice", "")
+        );
+        assert_eq!(creds.isolating(ProxyProtocol::Socks5), creds);
+    }
+
+    #[tokio::test]
+    async fn can_dial_through_proxy_chain() {
+        // A single mock plays both hops, the second handshake arrives through
+        // the tunnel of the first.
+        let proxy = mock_proxy(vec![
+            // The first hop only gets offered "no authentication".
+            (b"\x05\x01\x00", b"\x05\x00"),
+            (
+                b"\x05\x01\x00\x01\x0a\x00\x00\x02\x04\x38",
+                &[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+            ),
+            // The last hop gets the isolation credentials.
+            (
+                b"\x04\x01\x01\xbb\x00\x00\x00\x01key:alice\x00example.com\x00",
+                &[0x00, 0x5a, 0, 0, 0, 0, 0, 0],
+            ),
+        ])
+        .await;
+
+        let hop = ProxyHop::new("10.0.0.2", 1080, ProxyProtocol::Socks4a);
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(proxy)
+            .proxy_chain(vec![hop]);
+        let dest = TargetAddr::Domain("example.com".to_string(), 443);
+        let isolation = Credentials::new("key", "alice");
+        connect_to_proxy(&dest, &config, Some(&isolation))
+            .await
+            .unwrap();
+
+        let hop = ProxyHop::new("[::1]", 1080, ProxyProtocol::Socks5);
+        assert_eq!(hop.addr, TargetAddr::Ip("[::1]:1080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn can_listen_on_ip_address() {
+        use futures::StreamExt;
+        use libp2p::core::transport::ListenerEvent;
+
+        let config = Socks5TokioTcpConfig::default();
+        let addr = "/dns/example.com/tcp/443".parse().unwrap();
+        assert!(matches!(
+            config.clone().listen_on(addr),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+
+        let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        let mut listener = config.listen_on(addr).unwrap();
+        match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => {
+                let mut iter = addr.iter();
+                assert_eq!(iter.next(), Some(Protocol::Ip4([127, 0, 0, 1].into())));
+                assert!(matches!(iter.next(), Some(Protocol::Tcp(port)) if port != 0));
+            }
+            _ => panic!("expected a new listen address"),
+        }
+    }
+
+    #[tokio::test]
+    async fn onion_listener_reports_onion_address() {
+        use futures::StreamExt;
+        use libp2p::core::transport::ListenerEvent;
+
+        let onion: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234"
+                .parse()
+                .unwrap();
+        let mut map = HashMap::new();
+        map.insert(onion.clone(), 0);
+
+        let config = Socks5TokioTcpConfig::default().onion_map(map);
+        let mut listener = config.clone().listen_on(onion.clone()).unwrap();
+        match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => assert_eq!(addr, onion),
+            _ => panic!("expected the onion address"),
+        }
+
+        let mut listener = config.announce_loopback(true).listen_on(onion).unwrap();
+        let _ = listener.next().await;
+        match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => {
+                assert_eq!(
+                    addr.iter().next(),
+                    Some(Protocol::Ip4([127, 0, 0, 1].into()))
+                )
+            }
+            _ => panic!("expected the loopback address"),
+        }
+    }
+
+    #[test]
+    fn interface_changes_are_only_announced_if_asked_for() {
+        let socket_addr = "127.0.0.1:7777".parse().unwrap();
+
+        let mut addrs = Vec::new();
+        let mut pending: Buffer<TokioTcpTransStream> = Buffer::new();
+        check_for_interface_changes(&socket_addr, 7777, &mut addrs, &mut pending, false).unwrap();
+        assert!(!addrs.is_empty());
+        assert!(pending.is_empty());
+
+        let mut addrs = Vec::new();
+        check_for_interface_changes(&socket_addr, 7777, &mut addrs, &mut pending, true).unwrap();
+        assert_eq!(pending.len(), addrs.len());
+    }
+
+    #[tokio::test]
+    async fn creates_new_onion_service_through_control_port() {
+        use futures::StreamExt;
+        use libp2p::core::transport::ListenerEvent;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        const SERVICE_ID: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+        let mut control = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_port = control.local_addr().unwrap();
+        let (done_tx, done_rx) = futures::channel::oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            let (sock, _) = control.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            let mut line = String::new();
+            sock.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PROTOCOLINFO 1\r\n");
+            let reply = "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK\r\n";
+            sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+
+            line.clear();
+            sock.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "AUTHENTICATE\r\n");
+            sock.get_mut().write_all(b"250 OK\r\n").await.unwrap();
+
+            line.clear();
+            sock.read_line(&mut line).await.unwrap();
+            let add_onion = "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port=80,127.0.0.1:";
+            assert!(line.starts_with(add_onion));
+            let reply = format!("250-ServiceID={}\r\n250 OK\r\n", SERVICE_ID);
+            sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+
+            // Tor removes the service once the connection closes.
+            let _ = done_rx.await;
+            line.clear();
+            assert_eq!(sock.read_line(&mut line).await.unwrap(), 0);
+        });
+
+        let config = Socks5TokioTcpConfig::default().control_port(control_port);
+        let mut listener = config.listen_on(super::new_onion_service(80)).unwrap();
+        match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => {
+                assert_eq!(addr.to_string(), format!("/onion3/{}:80", SERVICE_ID))
+            }
+            _ => panic!("expected the onion address"),
+        }
+
+        drop(listener);
+        done_tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn can_listen_on_unix_socket() {
+        use futures::StreamExt;
+        use libp2p::core::transport::ListenerEvent;
+        use tokio::net::UnixStream;
+
+        let onion: Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:1234"
+                .parse()
+                .unwrap();
+        let name = format!("libp2p-tokio-socks5-{}.sock", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        // Leaves a stale socket file behind.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut map = HashMap::new();
+        map.insert(onion.clone(), super::LocalTarget::Unix(path.clone()));
+
+        let config = Socks5TokioTcpConfig::default().onion_targets(map);
+        let mut listener = config.listen_on(onion.clone()).unwrap();
+        match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => assert_eq!(addr, onion),
+            _ => panic!("expected the onion address"),
+        }
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        match listener.next().await {
+            Some(Ok(ListenerEvent::Upgrade { local_addr, .. })) => assert_eq!(local_addr, onion),
+            _ => panic!("expected an incoming connection"),
+        }
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn dialed_stream_records_destination() {
+        let proxy = mock_proxy(vec![
+            (b"\x05\x01\x02", b"\x05\x02"),
+            (b"\x01\x03key\x05alice", b"\x01\x00"),
+            (
+                b"\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+                &[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+            ),
+        ])
+        .await;
+
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(proxy)
+            .stream_isolation(StreamIsolation::Key("alice".to_string()));
+        let addr: Multiaddr = "/dns/example.com/tcp/443".parse().unwrap();
+        let stream = config.dial(addr.clone()).unwrap().await.unwrap();
+
+        assert_eq!(stream.destination(), Some(&addr));
+        assert_eq!(stream.proxy(), Some(&ProxyAddr::Socket(proxy)));
+        assert_eq!(stream.isolation_key(), Some("alice"));
+        assert!(stream.proxy_connected_at() <= stream.handshake_completed_at());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn can_dial_through_unix_socket_proxy() {
+        use crate::socks::tests::mock_unix_proxy;
+
+        let name = format!("libp2p-tokio-socks5-{}.sock", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        mock_unix_proxy(
+            &path,
+            vec![
+                (b"\x05\x01\x00", b"\x05\x00"),
+                (
+                    b"\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+                    &[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+                ),
+            ],
+        );
+
+        let proxy = ProxyAddr::Unix(path.clone());
+        let config = Socks5TokioTcpConfig::default().proxy_addr(proxy.clone());
+        let addr: Multiaddr = "/dns/example.com/tcp/443".parse().unwrap();
+        let stream = config.dial(addr.clone()).unwrap().await.unwrap();
+
+        assert_eq!(stream.destination(), Some(&addr));
+        assert_eq!(stream.proxy(), Some(&proxy));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_next_proxy_endpoint() {
+        use tokio::net::TcpListener;
+
+        // Nothing listens on the first endpoint.
+        let down = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let up = mock_proxy(vec![
+            (b"\x05\x01\x00", b"\x05\x00"),
+            (
+                b"\x05\x01\x00\x01\x0a\x00\x00\x02\x04\x38",
+                &[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+            ),
+        ])
+        .await;
+
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addrs(vec![down.into(), up.into()])
+            .proxy_selection(SelectionStrategy::Failover);
+        let dest = TargetAddr::Ip("10.0.0.2:1080".parse().unwrap());
+        connect_to_proxy(&dest, &config, None).await.unwrap();
+
+        assert_eq!(config.proxies.candidates(), vec![1, 0]);
+    }
+
+    #[tokio::test]
+    async fn proxy_connect_timeout_fails_with_specific_error() {
+        use crate::socks::tests::HangingListener;
+        use std::time::Duration;
+
+        let listener = HangingListener::new();
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(listener.addr)
+            .proxy_connect_timeout(Duration::from_millis(50));
+        let dest = TargetAddr::Domain("example.com".to_string(), 443);
+        let err = connect_to_proxy(&dest, &config, None).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            Socks5TransportError::ProxyConnectTimeout(d) if d == Duration::from_millis(50)
+        ));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_fails_with_specific_error() {
+        use std::time::Duration;
+
+        // Answers the greeting but never the CONNECT request.
+        let proxy = mock_proxy(vec![(b"\x05\x01\x00", b"\x05\x00")]).await;
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(proxy)
+            .connect_timeout(Duration::from_millis(50));
+        let dest = TargetAddr::Domain("example.com".to_string(), 443);
+        let err = connect_to_proxy(&dest, &config, None).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            Socks5TransportError::ConnectReplyTimeout(d) if d == Duration::from_millis(50)
+        ));
+    }
+
+    #[tokio::test]
+    async fn http_proxy_authentication_failure_is_classified() {
+        let proxy = mock_proxy(vec![(
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+        )])
+        .await;
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(proxy)
+            .protocol(ProxyProtocol::HttpConnect);
+        let dest = TargetAddr::Domain("example.com".to_string(), 443);
+        let err = connect_to_proxy(&dest, &config, None).await.unwrap_err();
+
+        match err {
+            Socks5TransportError::Authentication(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiation_timeout_fails_with_specific_error() {
+        use std::time::Duration;
+        use tokio::net::TcpListener;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+
+        // Accepts the connection but never answers the greeting.
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            futures_timer::Delay::new(Duration::from_secs(1)).await;
+            drop(sock);
+        });
+
+        let config = Socks5TokioTcpConfig::default()
+            .proxy_addr(proxy)
+            .negotiation_timeout(Duration::from_millis(50));
+        let dest = TargetAddr::Domain("example.com".to_string(), 443);
+        let err = connect_to_proxy(&dest, &config, None).await.unwrap_err();
 
-        assert_eq!(got, want);
+        assert!(matches!(
+            err,
+            Socks5TransportError::NegotiationTimeout(d) if d == Duration::from_millis(50)
+        ));
+        server.await.unwrap();
     }
 }
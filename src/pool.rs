@@ -0,0 +1,223 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Pool of proxy endpoints, picks the order in which the endpoints are tried
+//! for a dial and keeps track of their health.
+//!
+//! The state is shared between clones of the transport configuration, libp2p
+//! consumes a clone for every dial.
+
+use crate::ProxyAddr;
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// How long an endpoint that failed at the proxy connect phase is demoted to
+/// the end of the order.
+const DEFAULT_UNHEALTHY_PERIOD: Duration = Duration::from_secs(30);
+
+/// Strategy to pick the proxy endpoint for a dial.
+///
+/// Unhealthy endpoints are demoted, they are only tried once all healthy
+/// endpoints failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    /// Use the first healthy endpoint, in the order given.
+    #[default]
+    Failover,
+    /// Take turns between the healthy endpoints.
+    RoundRobin,
+    /// Use the healthy endpoint with the fewest dials in progress.
+    LeastOutstanding,
+}
+
+/// Proxy endpoints and their health.
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyPool {
+    endpoints: Vec<ProxyAddr>,
+    strategy: SelectionStrategy,
+    unhealthy_period: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Index of the endpoint to start with for `RoundRobin`.
+    next: usize,
+    /// Health of each endpoint, indexed as `ProxyPool::endpoints`.
+    health: Vec<Health>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Health {
+    /// Number of dials in progress through this endpoint.
+    outstanding: usize,
+    /// Endpoint is demoted until this instant.
+    unhealthy_until: Option<Instant>,
+}
+
+impl ProxyPool {
+    pub(crate) fn new(endpoints: Vec<ProxyAddr>) -> Self {
+        let state = State {
+            next: 0,
+            health: vec![Health::default(); endpoints.len()],
+        };
+        Self {
+            endpoints,
+            strategy: SelectionStrategy::default(),
+            unhealthy_period: DEFAULT_UNHEALTHY_PERIOD,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Replaces the endpoints, forgetting their health.
+    pub(crate) fn set_endpoints(&mut self, endpoints: Vec<ProxyAddr>) {
+        *self = Self {
+            strategy: self.strategy,
+            unhealthy_period: self.unhealthy_period,
+            ..Self::new(endpoints)
+        };
+    }
+
+    pub(crate) fn set_strategy(&mut self, strategy: SelectionStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub(crate) fn set_unhealthy_period(&mut self, period: Duration) {
+        self.unhealthy_period = period;
+    }
+
+    pub(crate) fn endpoint(&self, index: usize) -> &ProxyAddr {
+        &self.endpoints[index]
+    }
+
+    /// Returns the indices of the endpoints in the order they should be tried
+    /// for the next dial.
+    pub(crate) fn candidates(&self) -> Vec<usize> {
+        let mut state = self.state();
+        let now = Instant::now();
+        let (mut healthy, mut unhealthy): (Vec<_>, Vec<_>) = (0..self.endpoints.len())
+            .partition(|i| !matches!(state.health[*i].unhealthy_until, Some(t) if t > now));
+
+        match self.strategy {
+            SelectionStrategy::Failover => {}
+            // Takes turns between the healthy endpoints only, so that a demoted
+            // endpoint does not make the next one get two turns in a row.
+            SelectionStrategy::RoundRobin if !healthy.is_empty() => {
+                let turn = state.next % healthy.len();
+                healthy.rotate_left(turn);
+                state.next = state.next.wrapping_add(1);
+            }
+            SelectionStrategy::RoundRobin => {}
+            SelectionStrategy::LeastOutstanding => {
+                healthy.sort_by_key(|i| state.health[*i].outstanding);
+                unhealthy.sort_by_key(|i| state.health[*i].outstanding);
+            }
+        }
+
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Records the start of a dial through endpoint `index`, it ends when the
+    /// returned guard is dropped.
+    pub(crate) fn start_dial(&self, index: usize) -> Outstanding {
+        self.state().health[index].outstanding += 1;
+        Outstanding {
+            state: self.state.clone(),
+            index,
+        }
+    }
+
+    pub(crate) fn mark_healthy(&self, index: usize) {
+        self.state().health[index].unhealthy_until = None;
+    }
+
+    pub(crate) fn mark_unhealthy(&self, index: usize) {
+        self.state().health[index].unhealthy_until = Some(Instant::now() + self.unhealthy_period);
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A dial in progress through one of the endpoints.
+#[derive(Debug)]
+pub(crate) struct Outstanding {
+    state: Arc<Mutex<State>>,
+    index: usize,
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.health[self.index].outstanding -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: SelectionStrategy) -> ProxyPool {
+        let mut pool = ProxyPool::new(vec![
+            ProxyAddr::localhost(9050),
+            ProxyAddr::localhost(9052),
+            ProxyAddr::localhost(9054),
+        ]);
+        pool.set_strategy(strategy);
+        pool
+    }
+
+    #[test]
+    fn unhealthy_endpoints_are_tried_last() {
+        let pool = pool(SelectionStrategy::Failover);
+        pool.mark_unhealthy(0);
+        assert_eq!(pool.candidates(), vec![1, 2, 0]);
+
+        pool.mark_healthy(0);
+        assert_eq!(pool.candidates(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let pool = pool(SelectionStrategy::RoundRobin);
+        pool.mark_unhealthy(1);
+
+        assert_eq!(pool.candidates(), vec![0, 2, 1]);
+        assert_eq!(pool.candidates(), vec![2, 0, 1]);
+        assert_eq!(pool.candidates(), vec![0, 2, 1]);
+        assert_eq!(pool.candidates(), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn least_outstanding_picks_idle_endpoint() {
+        let pool = pool(SelectionStrategy::LeastOutstanding);
+        let first = pool.start_dial(0);
+        let _second = pool.start_dial(1);
+
+        assert_eq!(pool.candidates()[0], 2);
+        let _third = pool.start_dial(2);
+        drop(first);
+        assert_eq!(pool.candidates()[0], 0);
+    }
+}
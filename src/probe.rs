@@ -0,0 +1,254 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Readiness probe for the SOCKS5 proxy, lets services started alongside Tor
+//! wait for it before starting the swarm.
+
+use crate::{
+    no_proxy_endpoints,
+    socks::{self, AuthMethod},
+    timeout, ProxyAddr, Socks5TokioTcpConfig, Socks5TransportError,
+};
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use futures_timer::Delay;
+use log::debug;
+use std::{
+    cmp,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Delay before the first retry of `wait_until_ready`, doubled on every retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Upper bound for the delay between retries of `wait_until_ready`.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Result of a successful probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeReport {
+    /// The proxy endpoint that answered.
+    pub proxy: ProxyAddr,
+    /// Time taken to connect to the proxy and receive its method selection.
+    pub latency: Duration,
+    /// The authentication methods the proxy accepts.
+    pub auth_methods: Vec<AuthMethod>,
+}
+
+impl Socks5TokioTcpConfig {
+    /// Checks that a proxy endpoint is up and answers a SOCKS5 greeting.
+    ///
+    /// Endpoints are tried in the order a dial would try them, the report is
+    /// for the first one that answers. Each authentication method is offered
+    /// on its own connection to find out which ones the proxy accepts. This
+    /// speaks SOCKS5 regardless of the configured `ProxyProtocol`.
+    pub async fn probe(&self) -> Result<ProbeReport, Socks5TransportError> {
+        self.probe_tracking(&AtomicBool::new(false)).await
+    }
+
+    /// Like `probe`, sets `connected` while a connection to the proxy is
+    /// open so that an interrupted probe knows its phase.
+    async fn probe_tracking(
+        &self,
+        connected: &AtomicBool,
+    ) -> Result<ProbeReport, Socks5TransportError> {
+        let mut last_err = None;
+        for index in self.proxies.candidates() {
+            let proxy = self.proxies.endpoint(index);
+            match probe_endpoint(self, proxy, connected).await {
+                Ok(report) => {
+                    self.proxies.mark_healthy(index);
+                    return Ok(report);
+                }
+                Err(e) => {
+                    debug!("Probing proxy at {} failed: {}", proxy, e);
+                    if matches!(
+                        e,
+                        Socks5TransportError::ProxyConnect(_)
+                            | Socks5TransportError::ProxyConnectTimeout(_)
+                    ) {
+                        self.proxies.mark_unhealthy(index);
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(no_proxy_endpoints))
+    }
+
+    /// Probes the proxy until it is ready, backing off exponentially between
+    /// attempts. Gives up with the last probe error once `max_wait` elapsed, or
+    /// if the first probe did not finish in time with `ProxyConnectTimeout` or
+    /// `NegotiationTimeout`, depending on whether the proxy was connected.
+    pub async fn wait_until_ready(
+        &self,
+        max_wait: Duration,
+    ) -> Result<ProbeReport, Socks5TransportError> {
+        let deadline = Instant::now() + max_wait;
+        let mut backoff = INITIAL_BACKOFF;
+        let mut last_err = None;
+        loop {
+            // A probe can hang on a proxy that accepts connections but never
+            // answers, unless the configured timeouts catch it.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let connected = AtomicBool::new(false);
+            let probe = self.probe_tracking(&connected);
+            futures::pin_mut!(probe);
+            let err = match future::select(probe, Delay::new(remaining)).await {
+                Either::Left((Ok(report), _)) => return Ok(report),
+                Either::Left((Err(e), _)) => e,
+                Either::Right(_) => {
+                    let timed_out = if connected.load(Ordering::SeqCst) {
+                        Socks5TransportError::NegotiationTimeout(max_wait)
+                    } else {
+                        Socks5TransportError::ProxyConnectTimeout(max_wait)
+                    };
+                    return Err(last_err.unwrap_or(timed_out));
+                }
+            };
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(err);
+            }
+            debug!("Proxy not ready, retrying in {:?}: {}", backoff, err);
+            last_err = Some(err);
+            Delay::new(cmp::min(backoff, deadline - now)).await;
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+}
+
+async fn probe_endpoint(
+    config: &Socks5TokioTcpConfig,
+    proxy: &ProxyAddr,
+    connected: &AtomicBool,
+) -> Result<ProbeReport, Socks5TransportError> {
+    let start = Instant::now();
+    let mut auth_methods = Vec::new();
+    let mut latency = None;
+    for method in &[AuthMethod::NoAuth, AuthMethod::UsernamePassword] {
+        connected.store(false, Ordering::SeqCst);
+        let connect = proxy.connect().map_err(Socks5TransportError::ProxyConnect);
+        let mut stream = timeout(
+            config.proxy_connect_timeout,
+            connect,
+            Socks5TransportError::ProxyConnectTimeout,
+        )
+        .await?;
+        connected.store(true, Ordering::SeqCst);
+
+        let offer = socks::offer(&mut stream, *method)
+            .map_err(|e| Socks5TransportError::MethodNegotiation(e.into()));
+        let accepted = timeout(
+            config.negotiation_timeout,
+            offer,
+            Socks5TransportError::NegotiationTimeout,
+        )
+        .await?;
+
+        latency.get_or_insert_with(|| start.elapsed());
+        if accepted {
+            auth_methods.push(*method);
+        }
+    }
+
+    Ok(ProbeReport {
+        proxy: proxy.clone(),
+        latency: latency.unwrap_or_default(),
+        auth_methods,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks::tests::{mock_proxy_connections, HangingListener};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn probe_reports_accepted_auth_methods() {
+        let addr = mock_proxy_connections(vec![
+            vec![(b"\x05\x01\x00", b"\x05\x00")],
+            vec![(b"\x05\x01\x02", b"\x05\xff")],
+        ])
+        .await;
+
+        let config = Socks5TokioTcpConfig::default().proxy_addr(addr);
+        let report = config.probe().await.unwrap();
+
+        assert_eq!(report.proxy, ProxyAddr::Socket(addr));
+        assert_eq!(report.auth_methods, vec![AuthMethod::NoAuth]);
+    }
+
+    #[tokio::test]
+    async fn wait_until_ready_gives_up_after_max_wait() {
+        // Nothing listens on this port.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Socks5TokioTcpConfig::default().proxy_addr(addr);
+
+        let err = config
+            .wait_until_ready(Duration::from_millis(300))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Socks5TransportError::ProxyConnect(_)));
+    }
+
+    #[tokio::test]
+    async fn wait_until_ready_gives_up_on_silent_proxy() {
+        // Connections are accepted by the kernel but the greeting is never
+        // answered.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Socks5TokioTcpConfig::default().proxy_addr(addr);
+
+        let start = Instant::now();
+        let err = config
+            .wait_until_ready(Duration::from_millis(300))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Socks5TransportError::NegotiationTimeout(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn wait_until_ready_reports_hanging_connect() {
+        let listener = HangingListener::new();
+        let config = Socks5TokioTcpConfig::default().proxy_addr(listener.addr);
+
+        let err = config
+            .wait_until_ready(Duration::from_millis(300))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Socks5TransportError::ProxyConnectTimeout(_)));
+        drop(listener);
+    }
+}
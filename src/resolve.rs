@@ -0,0 +1,357 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Name resolution through Tor using its `RESOLVE` and `RESOLVE_PTR` SOCKS
+//! extensions, and a transport wrapper resolving `/dns*` addresses with it for
+//! transports that can not dial names. Unlike libp2p's `DnsConfig` no name
+//! ever reaches the local resolver.
+
+use crate::{
+    connect_to_endpoint, negotiate, socks, timeout, Listener, ProxyProtocol, Socks5TokioTcpConfig,
+    Socks5TransportError, TargetAddr,
+};
+use futures::{future, prelude::*};
+use libp2p::core::{
+    multiaddr::{Multiaddr, Protocol},
+    transport::{ListenerEvent, TransportError},
+    Transport,
+};
+use log::debug;
+use std::{error, fmt, io, net::IpAddr, pin::Pin};
+
+impl Socks5TokioTcpConfig {
+    /// Resolves `name` to an IP address through Tor.
+    ///
+    /// Needs a single SOCKS5 proxy, fails for other protocols and proxy
+    /// chains.
+    pub async fn resolve(&self, name: &str) -> Result<IpAddr, Socks5TransportError> {
+        self.check_can_resolve()?;
+        let target = TargetAddr::Domain(name.to_string(), 0);
+        let (mut stream, _, _dial) = connect_to_endpoint(self).await?;
+        negotiate(
+            &mut stream,
+            self,
+            self.credentials_for(&target, None).as_ref(),
+        )
+        .await?;
+
+        let resolve = socks::resolve(&mut stream, name)
+            .map_err(|e| Socks5TransportError::ConnectReply(e.into()));
+        timeout(
+            self.connect_timeout,
+            resolve,
+            Socks5TransportError::ConnectReplyTimeout,
+        )
+        .await
+    }
+
+    /// Resolves `ip` to a domain name through Tor.
+    ///
+    /// Needs a single SOCKS5 proxy, fails for other protocols and proxy
+    /// chains.
+    pub async fn resolve_ptr(&self, ip: IpAddr) -> Result<String, Socks5TransportError> {
+        self.check_can_resolve()?;
+        let target = TargetAddr::Ip((ip, 0).into());
+        let (mut stream, _, _dial) = connect_to_endpoint(self).await?;
+        negotiate(
+            &mut stream,
+            self,
+            self.credentials_for(&target, None).as_ref(),
+        )
+        .await?;
+
+        let resolve = socks::resolve_ptr(&mut stream, ip)
+            .map_err(|e| Socks5TransportError::ConnectReply(e.into()));
+        timeout(
+            self.connect_timeout,
+            resolve,
+            Socks5TransportError::ConnectReplyTimeout,
+        )
+        .await
+    }
+
+    /// `RESOLVE` is a SOCKS5 extension sent to the first proxy, it can not go
+    /// through other protocols or a proxy chain.
+    fn check_can_resolve(&self) -> Result<(), Socks5TransportError> {
+        if self.protocol != ProxyProtocol::Socks5 || !self.chain.is_empty() {
+            let msg = "resolving through Tor needs a single SOCKS5 proxy";
+            let e = io::Error::new(io::ErrorKind::InvalidInput, msg);
+            return Err(Socks5TransportError::Io(e));
+        }
+        Ok(())
+    }
+
+    /// Replaces the `/dns`, `/dns4` and `/dns6` components of `addr` with the
+    /// `/ip4` or `/ip6` address Tor resolves the name to. Onion service names
+    /// are kept, Tor refuses to resolve them.
+    pub async fn resolve_multiaddr(
+        &self,
+        addr: Multiaddr,
+    ) -> Result<Multiaddr, Socks5TransportError> {
+        let mut resolved = Multiaddr::empty();
+        for protocol in addr.iter() {
+            let (name, want_v4, want_v6) = match protocol {
+                Protocol::Dns(ref name) | Protocol::Dns4(ref name) | Protocol::Dns6(ref name)
+                    if is_onion(name) =>
+                {
+                    resolved.push(protocol);
+                    continue;
+                }
+                Protocol::Dns(ref name) => (name, true, true),
+                Protocol::Dns4(ref name) => (name, true, false),
+                Protocol::Dns6(ref name) => (name, false, true),
+                protocol => {
+                    resolved.push(protocol);
+                    continue;
+                }
+            };
+            let ip = self.resolve(name).await?;
+            debug!("Resolved {} to {} through Tor", name, ip);
+            match ip {
+                IpAddr::V4(ip) if want_v4 => resolved.push(Protocol::Ip4(ip)),
+                IpAddr::V6(ip) if want_v6 => resolved.push(Protocol::Ip6(ip)),
+                _ => {
+                    let msg = format!("{} resolved to {}, wrong address family", name, ip);
+                    let e = io::Error::new(io::ErrorKind::InvalidData, msg);
+                    return Err(Socks5TransportError::ConnectReply(e));
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// Wraps around a `Transport` and resolves `/dns`, `/dns4` and `/dns6`
+/// addresses through Tor if the inner transport can not dial them.
+///
+/// A replacement for libp2p's `DnsConfig` that does not leak names to the
+/// local resolver. Not needed for `Socks5TokioTcpConfig`, which dials names
+/// through the proxy itself. Addresses the inner transport supports, and onion
+/// service names, are passed on untouched.
+#[derive(Debug, Clone)]
+pub struct TorDnsConfig<T> {
+    inner: T,
+    resolver: Socks5TokioTcpConfig,
+}
+
+impl<T> TorDnsConfig<T> {
+    /// Creates a new transport resolving names with the proxy of `resolver`.
+    pub fn new(inner: T, resolver: Socks5TokioTcpConfig) -> Self {
+        Self { inner, resolver }
+    }
+}
+
+impl<T> Transport for TorDnsConfig<T>
+where
+    T: Transport + Clone + Send + 'static,
+    T::Output: Send,
+    T::Error: Send,
+    T::Listener: Send,
+    T::ListenerUpgrade: Send,
+    T::Dial: Send,
+{
+    type Output = T::Output;
+    type Error = TorDnsError<T::Error>;
+    type Listener = Listener<Self::ListenerUpgrade, Self::Error>;
+    type ListenerUpgrade = future::MapErr<T::ListenerUpgrade, fn(T::Error) -> Self::Error>;
+    type Dial = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        let listener = self
+            .inner
+            .listen_on(addr)
+            .map_err(|e| e.map(TorDnsError::Transport))?;
+        let listener = listener
+            .map_ok(|event: ListenerEvent<T::ListenerUpgrade, T::Error>| {
+                event
+                    .map(|upgrade| upgrade.map_err::<_, fn(_) -> _>(TorDnsError::Transport))
+                    .map_err(TorDnsError::Transport)
+            })
+            .map_err(TorDnsError::Transport);
+
+        Ok(Box::pin(listener))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let TorDnsConfig { inner, resolver } = self;
+        let addr = match inner.clone().dial(addr) {
+            Ok(dial) => return Ok(Box::pin(dial.map_err(TorDnsError::Transport))),
+            Err(TransportError::MultiaddrNotSupported(addr)) => addr,
+            Err(TransportError::Other(e)) => {
+                return Err(TransportError::Other(TorDnsError::Transport(e)))
+            }
+        };
+        let resolvable = addr.iter().any(|protocol| match protocol {
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => !is_onion(&name),
+            _ => false,
+        });
+        if !resolvable {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        // The name leaves through the `RESOLVE` request, so the dial has to
+        // pass the filter and routing policy before it is sent.
+        resolver
+            .check_filter(&addr)
+            .map_err(|e| TransportError::Other(TorDnsError::Resolve(e)))?;
+        for protocol in addr.iter() {
+            if let Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) = protocol {
+                resolver
+                    .route(&TargetAddr::Domain(name.into_owned(), 0))
+                    .map_err(|e| TransportError::Other(TorDnsError::Resolve(e)))?;
+            }
+        }
+
+        Ok(Box::pin(async move {
+            let addr = resolver
+                .resolve_multiaddr(addr)
+                .await
+                .map_err(TorDnsError::Resolve)?;
+            match inner.dial(addr) {
+                Ok(dial) => dial.await.map_err(TorDnsError::Transport),
+                Err(TransportError::MultiaddrNotSupported(addr)) => {
+                    Err(TorDnsError::MultiaddrNotSupported(addr))
+                }
+                Err(TransportError::Other(e)) => Err(TorDnsError::Transport(e)),
+            }
+        }))
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+/// Whether `name` is an onion service name.
+fn is_onion(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    name.to_ascii_lowercase().ends_with(".onion")
+}
+
+/// Error of the `TorDnsConfig` transport.
+#[derive(Debug)]
+pub enum TorDnsError<TErr> {
+    /// Error of the inner transport.
+    Transport(TErr),
+    /// Resolving a name through Tor failed.
+    Resolve(Socks5TransportError),
+    /// The inner transport does not support the resolved address.
+    MultiaddrNotSupported(Multiaddr),
+}
+
+impl<TErr> fmt::Display for TorDnsError<TErr>
+where
+    TErr: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorDnsError::Transport(e) => write!(f, "{}", e),
+            TorDnsError::Resolve(e) => write!(f, "resolving through Tor: {}", e),
+            TorDnsError::MultiaddrNotSupported(addr) => {
+                write!(f, "resolved address not supported: {}", addr)
+            }
+        }
+    }
+}
+
+impl<TErr> error::Error for TorDnsError<TErr>
+where
+    TErr: error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TorDnsError::Transport(e) => Some(e),
+            TorDnsError::Resolve(e) => Some(e),
+            TorDnsError::MultiaddrNotSupported(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{socks::tests::mock_proxy, Destination, DestinationPolicy, Route, RoutingPolicy};
+    use libp2p::core::transport::MemoryTransport;
+
+    #[tokio::test]
+    async fn can_resolve_dns4_multiaddr() {
+        let proxy = mock_proxy(vec![
+            (b"\x05\x01\x00", b"\x05\x00"),
+            (
+                b"\x05\xf0\x00\x03\x0bexample.com\x00\x00",
+                &[0x05, 0x00, 0x00, 0x01, 93, 184, 216, 34, 0, 0],
+            ),
+        ])
+        .await;
+
+        let config = Socks5TokioTcpConfig::default().proxy_addr(proxy);
+        let addr = "/dns4/example.com/tcp/443".parse().unwrap();
+        let resolved = config.resolve_multiaddr(addr).await.unwrap();
+
+        assert_eq!(resolved, "/ip4/93.184.216.34/tcp/443".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn keeps_onion_names() {
+        // Tor refuses to resolve onion names, they must not reach the proxy
+        let config = Socks5TokioTcpConfig::default();
+        for addr in &["/dns/example.onion/tcp/80", "/dns4/example.onion./tcp/80"] {
+            let addr: Multiaddr = addr.parse().unwrap();
+            let resolved = config.resolve_multiaddr(addr.clone()).await.unwrap();
+            assert_eq!(resolved, addr);
+        }
+    }
+
+    #[test]
+    fn filters_names_before_resolving() {
+        // Nothing listens on the proxy port, the dial must fail before
+        // reaching it.
+        let resolver = Socks5TokioTcpConfig::default()
+            .destination_filter(DestinationPolicy::default().allow_ports(vec![443]))
+            .routing_policy(RoutingPolicy::default().clearnet(Route::Reject));
+        let transport = TorDnsConfig::new(MemoryTransport, resolver);
+
+        match transport
+            .clone()
+            .dial("/dns4/example.com/tcp/80".parse().unwrap())
+        {
+            Err(TransportError::Other(TorDnsError::Resolve(
+                Socks5TransportError::DestinationRejected(..),
+            ))) => {}
+            _ => panic!("expected the destination filter to reject the dial"),
+        }
+        match transport.dial("/dns4/example.com/tcp/443".parse().unwrap()) {
+            Err(TransportError::Other(TorDnsError::Resolve(
+                Socks5TransportError::RoutingRejected(Destination::Clearnet),
+            ))) => {}
+            _ => panic!("expected the routing policy to reject the dial"),
+        }
+    }
+
+    #[tokio::test]
+    async fn resolving_needs_a_single_socks5_proxy() {
+        let config = Socks5TokioTcpConfig::default().protocol(ProxyProtocol::Socks4a);
+        match config.resolve("example.com").await {
+            Err(Socks5TransportError::Io(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Routing policy deciding, per class of destination, whether a dial goes
+//! through the proxy, directly over TCP or is rejected.
+//!
+//! Every decision is logged with target `libp2p_tokio_socks5::routing` so that
+//! it can be audited.
+
+use crate::socks::TargetAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Log target for routing decisions.
+pub(crate) const LOG_TARGET: &str = "libp2p_tokio_socks5::routing";
+
+/// What to do with a dial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Dial through the proxy.
+    Proxy,
+    /// Dial directly over TCP, domain names are resolved locally.
+    Direct,
+    /// Refuse to dial.
+    Reject,
+}
+
+/// Class of a dial destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// An onion service.
+    Onion,
+    /// A loopback or unspecified address, the latter reaches the local host.
+    Loopback,
+    /// A private, shared (carrier-grade NAT), unique local or link-local
+    /// address.
+    Private,
+    /// Any other IP address or domain name.
+    Clearnet,
+}
+
+impl Destination {
+    /// Classifies the dial target.
+    pub(crate) fn of(target: &TargetAddr) -> Self {
+        match target {
+            // A fully qualified name may end with a dot.
+            TargetAddr::Domain(name, _)
+                if name
+                    .strip_suffix('.')
+                    .unwrap_or(name)
+                    .to_ascii_lowercase()
+                    .ends_with(".onion") =>
+            {
+                Destination::Onion
+            }
+            TargetAddr::Domain(..) => Destination::Clearnet,
+            TargetAddr::Ip(addr) => Self::of_ip(addr),
+        }
+    }
+
+    fn of_ip(addr: &SocketAddr) -> Self {
+        // IPv4-mapped IPv6 addresses reach the IPv4 address.
+        let ip = match addr.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        match ip {
+            ip if ip.is_loopback() || ip.is_unspecified() => Destination::Loopback,
+            IpAddr::V4(ip) if ip.is_private() || ip.is_link_local() || is_shared(ip) => {
+                Destination::Private
+            }
+            IpAddr::V6(ip) => {
+                let first = ip.segments()[0];
+                // Unique local fc00::/7 and link-local fe80::/10.
+                if first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80 {
+                    Destination::Private
+                } else {
+                    Destination::Clearnet
+                }
+            }
+            IpAddr::V4(_) => Destination::Clearnet,
+        }
+    }
+}
+
+/// Whether `ip` is in the shared address space 100.64.0.0/10 (RFC 6598).
+fn is_shared(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && b & 0xc0 == 64
+}
+
+/// Routing policy for dials.
+///
+/// Onion addresses always go through the proxy. The default policy also routes
+/// every other destination through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutingPolicy {
+    clearnet: Route,
+    loopback: Route,
+    private: Route,
+}
+
+impl RoutingPolicy {
+    /// Sets the route for clearnet IP addresses and domain names.
+    pub fn clearnet(mut self, route: Route) -> Self {
+        self.clearnet = route;
+        self
+    }
+
+    /// Sets the route for loopback and unspecified addresses.
+    pub fn loopback(mut self, route: Route) -> Self {
+        self.loopback = route;
+        self
+    }
+
+    /// Sets the route for private, shared, unique local and link-local
+    /// addresses.
+    pub fn private(mut self, route: Route) -> Self {
+        self.private = route;
+        self
+    }
+
+    /// Returns the route for `destination`.
+    pub fn route(&self, destination: Destination) -> Route {
+        match destination {
+            Destination::Onion => Route::Proxy,
+            Destination::Loopback => self.loopback,
+            Destination::Private => self.private,
+            Destination::Clearnet => self.clearnet,
+        }
+    }
+}
+
+impl Default for RoutingPolicy {
+    fn default() -> Self {
+        Self {
+            clearnet: Route::Proxy,
+            loopback: Route::Proxy,
+            private: Route::Proxy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(s: &str) -> Destination {
+        let target = match s.parse() {
+            Ok(addr) => TargetAddr::Ip(addr),
+            Err(_) => {
+                let (name, port) = s.rsplit_once(':').unwrap();
+                TargetAddr::Domain(name.to_string(), port.parse().unwrap())
+            }
+        };
+        Destination::of(&target)
+    }
+
+    #[test]
+    fn can_classify_destinations() {
+        assert_eq!(
+            classify("vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion:1234"),
+            Destination::Onion
+        );
+        assert_eq!(
+            classify("VWW6YBAL4BD7SZMGNCYRUUCPGFKQAHZDDI37KTCEO3AH7NGMCOPNPYYD.onion.:1234"),
+            Destination::Onion
+        );
+        assert_eq!(classify("127.0.0.1:80"), Destination::Loopback);
+        assert_eq!(classify("[::1]:80"), Destination::Loopback);
+        assert_eq!(classify("192.168.1.10:80"), Destination::Private);
+        assert_eq!(classify("[fd00::1]:80"), Destination::Private);
+        assert_eq!(classify("[fe80::1]:80"), Destination::Private);
+        assert_eq!(classify("203.0.113.7:80"), Destination::Clearnet);
+        assert_eq!(classify("[2001:db8::1]:80"), Destination::Clearnet);
+        assert_eq!(classify("example.com:443"), Destination::Clearnet);
+    }
+
+    #[test]
+    fn classifies_mapped_unspecified_and_shared_addresses() {
+        assert_eq!(classify("[::ffff:192.168.1.1]:22"), Destination::Private);
+        assert_eq!(classify("[::ffff:127.0.0.1]:22"), Destination::Loopback);
+        assert_eq!(classify("[::ffff:203.0.113.7]:22"), Destination::Clearnet);
+        assert_eq!(classify("0.0.0.0:22"), Destination::Loopback);
+        assert_eq!(classify("[::]:22"), Destination::Loopback);
+        assert_eq!(classify("100.64.0.1:22"), Destination::Private);
+        assert_eq!(classify("100.128.0.1:22"), Destination::Clearnet);
+        assert_eq!(classify("169.254.1.1:22"), Destination::Private);
+    }
+
+    #[test]
+    fn onion_always_goes_through_proxy() {
+        let policy = RoutingPolicy::default()
+            .clearnet(Route::Reject)
+            .loopback(Route::Direct)
+            .private(Route::Reject);
+
+        assert_eq!(policy.route(Destination::Onion), Route::Proxy);
+        assert_eq!(policy.route(Destination::Clearnet), Route::Reject);
+        assert_eq!(policy.route(Destination::Loopback), Route::Direct);
+    }
+}
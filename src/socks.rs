@@ -0,0 +1,813 @@
+// Copyright 2021 CoBloX Pty Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Client side of the SOCKS5 protocol (RFC 1928) including username/password
+//! authentication (RFC 1929), and of the legacy SOCKS4 and SOCKS4a protocols.
+//!
+//! The handshake runs over any `AsyncRead + AsyncWrite` stream so that it can
+//! be used with TCP as well as Unix domain socket connections to the proxy.
+
+use crate::Credentials;
+use std::{
+    error, fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+const SOCKS5_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+/// Tor extension, resolves a domain name.
+const CMD_RESOLVE: u8 = 0xf0;
+/// Tor extension, reverse resolves an IP address.
+const CMD_RESOLVE_PTR: u8 = 0xf1;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const SOCKS4_REPLY_GRANTED: u8 = 0x5a;
+
+/// SOCKS5 authentication method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// No authentication required.
+    NoAuth,
+    /// Username/password authentication (RFC 1929).
+    UsernamePassword,
+}
+
+impl AuthMethod {
+    fn code(self) -> u8 {
+        match self {
+            AuthMethod::NoAuth => METHOD_NO_AUTH,
+            AuthMethod::UsernamePassword => METHOD_USERNAME_PASSWORD,
+        }
+    }
+}
+
+/// The target of a SOCKS5 request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    /// An IP address and port.
+    Ip(SocketAddr),
+    /// A domain name and port, resolved by the proxy.
+    Domain(String, u16),
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+/// Errors during the SOCKS5 handshake.
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error on the connection to the proxy.
+    Io(io::Error),
+    /// The target address can not be encoded in a SOCKS5 request.
+    InvalidTargetAddress(&'static str),
+    /// The credentials can not be encoded in a RFC 1929 request.
+    InvalidAuthValues(&'static str),
+    /// The proxy replied with an unexpected protocol version.
+    InvalidResponseVersion(u8),
+    /// The proxy accepts none of the authentication methods we offered.
+    NoAcceptableAuthMethods,
+    /// The proxy selected an authentication method we did not offer.
+    UnknownAuthMethod(u8),
+    /// The proxy rejected our username/password, contains the status code.
+    PasswordAuthFailure(u8),
+    /// The proxy failed the request.
+    Reply(ReplyError),
+    /// The reserved byte of the reply is not zero.
+    InvalidReservedByte(u8),
+    /// The reply contains an unknown address type.
+    UnknownAddressType(u8),
+    /// The reply to a `RESOLVE` or `RESOLVE_PTR` contains the wrong kind of
+    /// address.
+    UnexpectedReplyAddress(TargetAddr),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidTargetAddress(msg) => write!(f, "target address is invalid: {}", msg),
+            Error::InvalidAuthValues(msg) => write!(f, "invalid auth values: {}", msg),
+            Error::InvalidResponseVersion(v) => write!(f, "invalid response version: {}", v),
+            Error::NoAcceptableAuthMethods => write!(f, "no acceptable auth methods"),
+            Error::UnknownAuthMethod(m) => write!(f, "unknown auth method: {:#04x}", m),
+            Error::PasswordAuthFailure(code) => {
+                write!(f, "password auth failure, code: {:#04x}", code)
+            }
+            Error::Reply(e) => write!(f, "{}", e),
+            Error::InvalidReservedByte(b) => write!(f, "invalid reserved byte: {:#04x}", b),
+            Error::UnknownAddressType(a) => write!(f, "unknown address type: {:#04x}", a),
+            Error::UnexpectedReplyAddress(a) => write!(f, "unexpected address in reply: {}", a),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Reply(reply) => io::Error::new(reply.kind(), reply),
+            e => io::Error::new(io::ErrorKind::ConnectionRefused, e),
+        }
+    }
+}
+
+/// Reply code of a failed proxy request.
+///
+/// Includes Tor's extended error codes, sent when the `SocksPort` has the
+/// `ExtendedErrors` flag. Dial errors caused by a failed request carry this:
+///
+/// ```
+/// # use libp2p_tokio_socks5::{ReplyError, Socks5TransportError};
+/// fn onion_offline(err: &Socks5TransportError) -> bool {
+///     err.reply() == Some(ReplyError::OnionDescriptorNotFound)
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyError {
+    /// General SOCKS server failure (0x01).
+    GeneralFailure,
+    /// Connection not allowed by ruleset (0x02).
+    NotAllowed,
+    /// Network unreachable (0x03).
+    NetworkUnreachable,
+    /// Host unreachable (0x04).
+    HostUnreachable,
+    /// Connection refused (0x05).
+    ConnectionRefused,
+    /// TTL expired (0x06).
+    TtlExpired,
+    /// Command not supported (0x07).
+    CommandNotSupported,
+    /// Address type not supported (0x08).
+    AddressTypeNotSupported,
+    /// SOCKS4 request rejected or failed (0x5b).
+    Socks4Rejected,
+    /// SOCKS4 request rejected, identd unreachable (0x5c).
+    Socks4IdentdUnreachable,
+    /// SOCKS4 request rejected, identd user id mismatch (0x5d).
+    Socks4IdentdMismatch,
+    /// Tor: onion service descriptor can not be found (0xf0).
+    OnionDescriptorNotFound,
+    /// Tor: onion service descriptor is invalid (0xf1).
+    OnionDescriptorInvalid,
+    /// Tor: onion service introduction failed (0xf2).
+    OnionIntroFailed,
+    /// Tor: onion service rendezvous failed (0xf3).
+    OnionRendezvousFailed,
+    /// Tor: onion service requires client authorization, we have none (0xf4).
+    OnionClientAuthMissing,
+    /// Tor: onion service rejected our client authorization (0xf5).
+    OnionClientAuthBad,
+    /// Tor: onion address is invalid (0xf6).
+    OnionBadAddress,
+    /// Tor: onion service introduction timed out (0xf7).
+    OnionIntroTimedOut,
+    /// Any other reply code.
+    Other(u8),
+}
+
+impl ReplyError {
+    /// Maps a reply code, other than success, to the error.
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => ReplyError::GeneralFailure,
+            0x02 => ReplyError::NotAllowed,
+            0x03 => ReplyError::NetworkUnreachable,
+            0x04 => ReplyError::HostUnreachable,
+            0x05 => ReplyError::ConnectionRefused,
+            0x06 => ReplyError::TtlExpired,
+            0x07 => ReplyError::CommandNotSupported,
+            0x08 => ReplyError::AddressTypeNotSupported,
+            0x5b => ReplyError::Socks4Rejected,
+            0x5c => ReplyError::Socks4IdentdUnreachable,
+            0x5d => ReplyError::Socks4IdentdMismatch,
+            0xf0 => ReplyError::OnionDescriptorNotFound,
+            0xf1 => ReplyError::OnionDescriptorInvalid,
+            0xf2 => ReplyError::OnionIntroFailed,
+            0xf3 => ReplyError::OnionRendezvousFailed,
+            0xf4 => ReplyError::OnionClientAuthMissing,
+            0xf5 => ReplyError::OnionClientAuthBad,
+            0xf6 => ReplyError::OnionBadAddress,
+            0xf7 => ReplyError::OnionIntroTimedOut,
+            other => ReplyError::Other(other),
+        }
+    }
+
+    /// The reply code sent by the proxy.
+    pub fn code(&self) -> u8 {
+        match self {
+            ReplyError::GeneralFailure => 0x01,
+            ReplyError::NotAllowed => 0x02,
+            ReplyError::NetworkUnreachable => 0x03,
+            ReplyError::HostUnreachable => 0x04,
+            ReplyError::ConnectionRefused => 0x05,
+            ReplyError::TtlExpired => 0x06,
+            ReplyError::CommandNotSupported => 0x07,
+            ReplyError::AddressTypeNotSupported => 0x08,
+            ReplyError::Socks4Rejected => 0x5b,
+            ReplyError::Socks4IdentdUnreachable => 0x5c,
+            ReplyError::Socks4IdentdMismatch => 0x5d,
+            ReplyError::OnionDescriptorNotFound => 0xf0,
+            ReplyError::OnionDescriptorInvalid => 0xf1,
+            ReplyError::OnionIntroFailed => 0xf2,
+            ReplyError::OnionRendezvousFailed => 0xf3,
+            ReplyError::OnionClientAuthMissing => 0xf4,
+            ReplyError::OnionClientAuthBad => 0xf5,
+            ReplyError::OnionBadAddress => 0xf6,
+            ReplyError::OnionIntroTimedOut => 0xf7,
+            ReplyError::Other(code) => *code,
+        }
+    }
+
+    /// The `io::ErrorKind` closest to this reply.
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            ReplyError::OnionDescriptorNotFound => io::ErrorKind::NotFound,
+            ReplyError::NotAllowed
+            | ReplyError::OnionClientAuthMissing
+            | ReplyError::OnionClientAuthBad => io::ErrorKind::PermissionDenied,
+            ReplyError::OnionBadAddress | ReplyError::AddressTypeNotSupported => {
+                io::ErrorKind::InvalidInput
+            }
+            ReplyError::TtlExpired | ReplyError::OnionIntroTimedOut => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::ConnectionRefused,
+        }
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ReplyError::GeneralFailure => "general SOCKS server failure",
+            ReplyError::NotAllowed => "connection not allowed by ruleset",
+            ReplyError::NetworkUnreachable => "network unreachable",
+            ReplyError::HostUnreachable => "host unreachable",
+            ReplyError::ConnectionRefused => "connection refused",
+            ReplyError::TtlExpired => "TTL expired",
+            ReplyError::CommandNotSupported => "command not supported",
+            ReplyError::AddressTypeNotSupported => "address type not supported",
+            ReplyError::Socks4Rejected => "request rejected or failed",
+            ReplyError::Socks4IdentdUnreachable => "request rejected, identd unreachable",
+            ReplyError::Socks4IdentdMismatch => "request rejected, identd user id mismatch",
+            ReplyError::OnionDescriptorNotFound => "onion service descriptor not found",
+            ReplyError::OnionDescriptorInvalid => "onion service descriptor is invalid",
+            ReplyError::OnionIntroFailed => "onion service introduction failed",
+            ReplyError::OnionRendezvousFailed => "onion service rendezvous failed",
+            ReplyError::OnionClientAuthMissing => "onion service client authorization missing",
+            ReplyError::OnionClientAuthBad => "onion service client authorization rejected",
+            ReplyError::OnionBadAddress => "invalid onion address",
+            ReplyError::OnionIntroTimedOut => "onion service introduction timed out",
+            ReplyError::Other(code) => return write!(f, "unknown reply code: {:#04x}", code),
+        };
+        f.write_str(msg)
+    }
+}
+
+impl ReplyError {
+    /// Whether dialing again, later, may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ReplyError::GeneralFailure
+                | ReplyError::NetworkUnreachable
+                | ReplyError::HostUnreachable
+                | ReplyError::TtlExpired
+                | ReplyError::OnionDescriptorNotFound
+                | ReplyError::OnionIntroFailed
+                | ReplyError::OnionRendezvousFailed
+                | ReplyError::OnionIntroTimedOut
+        )
+    }
+}
+
+impl error::Error for ReplyError {}
+
+/// Asks the SOCKS5 proxy on `stream` to connect to `target`, this must follow
+/// `negotiate` and, if required, `authenticate`. On success the stream is
+/// tunneled through to the target.
+pub async fn connect<S>(stream: &mut S, target: &TargetAddr) -> Result<TargetAddr, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    request(stream, CMD_CONNECT, target).await
+}
+
+/// Asks Tor on `stream` to resolve `name` using its `RESOLVE` extension, this
+/// must follow `negotiate` and, if required, `authenticate`.
+pub async fn resolve<S>(stream: &mut S, name: &str) -> Result<IpAddr, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let target = TargetAddr::Domain(name.to_string(), 0);
+    match request(stream, CMD_RESOLVE, &target).await? {
+        TargetAddr::Ip(addr) => Ok(addr.ip()),
+        other => Err(Error::UnexpectedReplyAddress(other)),
+    }
+}
+
+/// Asks Tor on `stream` for the domain name of `ip` using its `RESOLVE_PTR`
+/// extension, this must follow `negotiate` and, if required, `authenticate`.
+pub async fn resolve_ptr<S>(stream: &mut S, ip: IpAddr) -> Result<String, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let target = TargetAddr::Ip(SocketAddr::new(ip, 0));
+    match request(stream, CMD_RESOLVE_PTR, &target).await? {
+        TargetAddr::Domain(name, _) => Ok(name),
+        other => Err(Error::UnexpectedReplyAddress(other)),
+    }
+}
+
+/// Runs the SOCKS4 handshake on `stream` asking the proxy to connect to
+/// `target`. With `resolve_remotely` (SOCKS4a) domain names are sent to the
+/// proxy, otherwise `target` must be an IPv4 address.
+///
+/// SOCKS4 has no passwords, the username of `credentials` is sent as user id.
+/// Stream isolation credentials are folded into the username beforehand.
+pub async fn connect_v4<S>(
+    stream: &mut S,
+    target: &TargetAddr,
+    credentials: Option<&Credentials>,
+    resolve_remotely: bool,
+) -> Result<TargetAddr, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let user_id = credentials
+        .map(|c| c.username.as_bytes())
+        .unwrap_or_default();
+    if user_id.contains(&0) {
+        return Err(Error::InvalidAuthValues("user id must not contain NUL"));
+    }
+
+    let mut req = vec![SOCKS4_VERSION, CMD_CONNECT];
+    let domain = match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            req.extend_from_slice(&addr.port().to_be_bytes());
+            req.extend_from_slice(&addr.ip().octets());
+            None
+        }
+        TargetAddr::Ip(SocketAddr::V6(_)) => {
+            return Err(Error::InvalidTargetAddress(
+                "SOCKS4 does not support IPv6 addresses",
+            ))
+        }
+        TargetAddr::Domain(name, port) if resolve_remotely => {
+            if name.is_empty() || name.as_bytes().contains(&0) {
+                return Err(Error::InvalidTargetAddress("invalid domain name"));
+            }
+            req.extend_from_slice(&port.to_be_bytes());
+            // SOCKS4a: an invalid IP address of the form 0.0.0.x with x non-zero
+            // tells the proxy that the domain name follows the user id.
+            req.extend_from_slice(&[0, 0, 0, 1]);
+            Some(name)
+        }
+        TargetAddr::Domain(..) => {
+            return Err(Error::InvalidTargetAddress(
+                "SOCKS4 requires an IPv4 address, use SOCKS4a for domain names",
+            ))
+        }
+    };
+    req.extend_from_slice(user_id);
+    req.push(0x00);
+    if let Some(name) = domain {
+        req.extend_from_slice(name.as_bytes());
+        req.push(0x00);
+    }
+    stream.write_all(&req).await?;
+
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != SOCKS4_REPLY_VERSION {
+        return Err(Error::InvalidResponseVersion(buf[0]));
+    }
+    if buf[1] != SOCKS4_REPLY_GRANTED {
+        return Err(Error::Reply(ReplyError::from_code(buf[1])));
+    }
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+    Ok(TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port))))
+}
+
+/// Negotiates the authentication method with the SOCKS5 proxy on `stream`.
+/// Returns true if the proxy selected username/password authentication, which
+/// is only offered if we have `credentials`.
+pub async fn negotiate<S>(stream: &mut S, credentials: Option<&Credentials>) -> Result<bool, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Only offer username/password if we have credentials, Tor stream isolation
+    // depends on the proxy not picking "no authentication" instead.
+    let method = match credentials {
+        Some(_) => AuthMethod::UsernamePassword,
+        None => AuthMethod::NoAuth,
+    };
+    if !offer(stream, method).await? {
+        return Err(Error::NoAcceptableAuthMethods);
+    }
+    Ok(method == AuthMethod::UsernamePassword)
+}
+
+/// Sends a greeting offering only `method` to the SOCKS5 proxy on `stream`.
+/// Returns whether the proxy accepted it.
+pub async fn offer<S>(stream: &mut S, method: AuthMethod) -> Result<bool, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[SOCKS5_VERSION, 1, method.code()])
+        .await?;
+
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != SOCKS5_VERSION {
+        return Err(Error::InvalidResponseVersion(buf[0]));
+    }
+    match buf[1] {
+        METHOD_NO_ACCEPTABLE => Ok(false),
+        selected if selected == method.code() => Ok(true),
+        other => Err(Error::UnknownAuthMethod(other)),
+    }
+}
+
+/// Username/password authentication as per RFC 1929.
+pub async fn authenticate<S>(stream: &mut S, credentials: &Credentials) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    if username.is_empty() || username.len() > 255 {
+        return Err(Error::InvalidAuthValues("username length should be 1-255"));
+    }
+    if password.is_empty() || password.len() > 255 {
+        return Err(Error::InvalidAuthValues("password length should be 1-255"));
+    }
+
+    let mut req = Vec::with_capacity(3 + username.len() + password.len());
+    req.push(AUTH_VERSION);
+    req.push(username.len() as u8);
+    req.extend_from_slice(username);
+    req.push(password.len() as u8);
+    req.extend_from_slice(password);
+    stream.write_all(&req).await?;
+
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != AUTH_VERSION {
+        return Err(Error::InvalidResponseVersion(buf[0]));
+    }
+    if buf[1] != 0 {
+        return Err(Error::PasswordAuthFailure(buf[1]));
+    }
+
+    Ok(())
+}
+
+/// Sends the request `cmd` for `target` and reads the reply, returning the
+/// address bound by the proxy.
+async fn request<S>(stream: &mut S, cmd: u8, target: &TargetAddr) -> Result<TargetAddr, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut req = vec![SOCKS5_VERSION, cmd, 0x00];
+    encode_addr(target, &mut req)?;
+    stream.write_all(&req).await?;
+
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != SOCKS5_VERSION {
+        return Err(Error::InvalidResponseVersion(buf[0]));
+    }
+    if buf[1] != REPLY_SUCCEEDED {
+        return Err(Error::Reply(ReplyError::from_code(buf[1])));
+    }
+    if buf[2] != 0x00 {
+        return Err(Error::InvalidReservedByte(buf[2]));
+    }
+
+    read_addr(stream, buf[3]).await
+}
+
+/// Appends the SOCKS5 encoding of `target` (ATYP, address, port) to `buf`.
+fn encode_addr(target: &TargetAddr, buf: &mut Vec<u8>) -> Result<(), Error> {
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(name, port) => {
+            let name = name.as_bytes();
+            if name.is_empty() || name.len() > 255 {
+                return Err(Error::InvalidTargetAddress(
+                    "domain name length should be 1-255",
+                ));
+            }
+            buf.push(ATYP_DOMAIN);
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name);
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads an address of type `atyp` followed by a port.
+async fn read_addr<S>(stream: &mut S, atyp: u8) -> Result<TargetAddr, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let addr = match atyp {
+        ATYP_IPV4 => {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            let port = stream.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(buf), port)))
+        }
+        ATYP_IPV6 => {
+            let mut buf = [0u8; 16];
+            stream.read_exact(&mut buf).await?;
+            let port = stream.read_u16().await?;
+            TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(buf),
+                port,
+                0,
+                0,
+            )))
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut buf = vec![0u8; len as usize];
+            stream.read_exact(&mut buf).await?;
+            let port = stream.read_u16().await?;
+            let name = String::from_utf8_lossy(&buf).into_owned();
+            TargetAddr::Domain(name, port)
+        }
+        other => return Err(Error::UnknownAddressType(other)),
+    };
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::path::Path;
+    #[cfg(unix)]
+    use tokio::net::UnixListener;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// What a client sends to a mock proxy, in order, with the replies.
+    pub(crate) type Script = Vec<(&'static [u8], &'static [u8])>;
+
+    /// Accepts a connection and checks what the client sends against the
+    /// script, answering with the scripted replies.
+    pub(crate) async fn mock_proxy(script: Script) -> SocketAddr {
+        mock_proxy_connections(vec![script]).await
+    }
+
+    /// Like `mock_proxy`, accepting a connection for each script in turn.
+    pub(crate) async fn mock_proxy_connections(scripts: Vec<Script>) -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for script in scripts {
+                let (sock, _) = listener.accept().await.unwrap();
+                play(sock, script).await;
+            }
+        });
+        addr
+    }
+
+    /// Like `mock_proxy`, listening on a Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub(crate) fn mock_unix_proxy(path: &Path, script: Script) {
+        let mut listener = UnixListener::bind(path).unwrap();
+
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            play(sock, script).await;
+        });
+    }
+
+    async fn play<S>(mut sock: S, script: Script)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        for (expected, reply) in script {
+            let mut received = vec![0; expected.len()];
+            sock.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            sock.write_all(reply).await.unwrap();
+        }
+        // Anything else is never answered, the client closes the connection.
+        let _ = sock.read_to_end(&mut Vec::new()).await;
+    }
+
+    /// A listener with a full accept queue, the kernel drops further connection
+    /// attempts so connecting to `addr` hangs.
+    pub(crate) struct HangingListener {
+        pub(crate) addr: SocketAddr,
+        _socket: socket2::Socket,
+        _queued: Vec<std::net::TcpStream>,
+    }
+
+    impl HangingListener {
+        pub(crate) fn new() -> Self {
+            use socket2::{Domain, SockAddr, Socket, Type};
+
+            let socket = Socket::new(Domain::ipv4(), Type::stream(), None).unwrap();
+            let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            socket.bind(&SockAddr::from(any)).unwrap();
+            socket.listen(0).unwrap();
+            let addr = SocketAddr::V4(socket.local_addr().unwrap().as_inet().unwrap());
+            let queued = (0..3)
+                .map_while(|_| {
+                    let timeout = std::time::Duration::from_millis(100);
+                    std::net::TcpStream::connect_timeout(&addr, timeout).ok()
+                })
+                .collect();
+            HangingListener {
+                addr,
+                _socket: socket,
+                _queued: queued,
+            }
+        }
+    }
+
+    async fn handshake(
+        stream: &mut TcpStream,
+        target: &TargetAddr,
+        credentials: Option<&Credentials>,
+    ) -> Result<TargetAddr, Error> {
+        if negotiate(stream, credentials).await? {
+            authenticate(stream, credentials.unwrap()).await?;
+        }
+        connect(stream, target).await
+    }
+
+    #[tokio::test]
+    async fn connect_with_credentials() {
+        let addr = mock_proxy(vec![
+            (b"\x05\x01\x02", b"\x05\x02"),
+            (b"\x01\x03bob\x03pwd", b"\x01\x00"),
+            (
+                b"\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+                &[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90],
+            ),
+        ])
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let target = TargetAddr::Domain("example.com".to_string(), 443);
+        let creds = Credentials::new("bob", "pwd");
+        let bound = handshake(&mut stream, &target, Some(&creds)).await.unwrap();
+
+        assert_eq!(bound, TargetAddr::Ip("127.0.0.1:8080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn connect_reports_reply_code() {
+        let addr = mock_proxy(vec![
+            (b"\x05\x01\x00", b"\x05\x00"),
+            (
+                b"\x05\x01\x00\x01\xc0\x00\x02\x01\x00\x50",
+                &[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+            ),
+        ])
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let target = TargetAddr::Ip("192.0.2.1:80".parse().unwrap());
+        let err = handshake(&mut stream, &target, None).await.unwrap_err();
+
+        assert!(matches!(err, Error::Reply(ReplyError::ConnectionRefused)));
+    }
+
+    #[tokio::test]
+    async fn can_resolve_through_tor() {
+        let addr = mock_proxy(vec![
+            (
+                b"\x05\xf0\x00\x03\x0bexample.com\x00\x00",
+                &[0x05, 0x00, 0x00, 0x01, 93, 184, 216, 34, 0, 0],
+            ),
+            (
+                b"\x05\xf1\x00\x01\x5d\xb8\xd8\x22\x00\x00",
+                b"\x05\x00\x00\x03\x0bexample.com\x00\x00",
+            ),
+        ])
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let ip = resolve(&mut stream, "example.com").await.unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+
+        let name = resolve_ptr(&mut stream, ip).await.unwrap();
+        assert_eq!(name, "example.com");
+    }
+
+    #[tokio::test]
+    async fn connect_v4a_sends_domain_name() {
+        let addr = mock_proxy(vec![(
+            b"\x04\x01\x01\xbb\x00\x00\x00\x01bob\x00example.com\x00",
+            &[0x00, 0x5a, 0, 0, 0, 0, 0, 0],
+        )])
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let target = TargetAddr::Domain("example.com".to_string(), 443);
+        let creds = Credentials::new("bob", "unused");
+        connect_v4(&mut stream, &target, Some(&creds), true)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_v4_rejects_domain_name() {
+        let addr = mock_proxy(vec![]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let target = TargetAddr::Domain("example.com".to_string(), 443);
+        let err = connect_v4(&mut stream, &target, None, false)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::InvalidTargetAddress(_)));
+    }
+
+    #[test]
+    fn tor_extended_errors_keep_their_reason() {
+        for code in 0xf0..=0xf7 {
+            let reply = ReplyError::from_code(code);
+            assert!(!matches!(reply, ReplyError::Other(_)));
+            assert_eq!(reply.code(), code);
+        }
+
+        let err = io::Error::from(Error::Reply(ReplyError::OnionClientAuthMissing));
+        let inner = err.get_ref().and_then(|e| e.downcast_ref::<ReplyError>());
+
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(inner, Some(&ReplyError::OnionClientAuthMissing));
+    }
+}